@@ -0,0 +1,271 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use reqwest::{Certificate, Client, Identity, Url};
+
+/// Options for the clients which connect to the origins of an
+/// [`OriginPool`](crate::OriginPool), such as which certificates to trust.
+///
+/// Each origin has its own client, built from the same options.
+#[derive(Clone, Default)]
+pub struct ClientOptions {
+    root_certificates: Vec<Certificate>,
+    identity: Option<Identity>,
+    server_name: Option<String>,
+    resolve: Vec<(String, SocketAddr)>,
+}
+
+impl ClientOptions {
+    /// Create a new [`ClientOptions`], which trusts the system root certificates and
+    /// resolves hostnames with the system resolver.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the CA certificates in the PEM bundle `pem` for upstream servers, in addition
+    /// to the system root certificates.
+    pub fn with_root_certificates(mut self, pem: &[u8]) -> reqwest::Result<Self> {
+        self.root_certificates
+            .extend(Certificate::from_pem_bundle(pem)?);
+        Ok(self)
+    }
+
+    /// Present the PEM certificate chain `cert`, whose PKCS #8 PEM private key is `key`, to
+    /// upstream servers which require a client certificate.
+    pub fn with_identity(mut self, cert: &[u8], key: &[u8]) -> reqwest::Result<Self> {
+        self.identity = Some(Identity::from_pkcs8_pem(cert, key)?);
+        Ok(self)
+    }
+
+    /// Connect to origins given by IP address as `name`, which is sent for SNI, checked
+    /// against their certificates, and sent in the `host` header. Origins given by
+    /// hostname are unaffected.
+    pub fn with_server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(name.to_owned());
+        self
+    }
+
+    /// Connect to `addr` for origins with the hostname `host`, rather than resolving it.
+    ///
+    /// If the port of `addr` is 0, the port of the origin is used.
+    pub fn with_resolve(mut self, host: &str, addr: SocketAddr) -> Self {
+        self.resolve.push((host.to_owned(), addr));
+        self
+    }
+
+    /// Build the client for the origin at `base_url`, and the base URL of requests
+    /// through it, which has the hostname replaced by the server name if one is set.
+    pub(crate) fn build(&self, base_url: &Url) -> (Client, Url) {
+        let mut builder = Client::builder()
+            .redirect(reqwest::redirect::Policy::limited(1))
+            .connect_timeout(Duration::from_secs(10))
+            .http2_adaptive_window(true);
+
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+
+        for (host, addr) in &self.resolve {
+            builder = builder.resolve(host, *addr);
+        }
+
+        let mut url = base_url.clone();
+
+        if let (Some(name), Some(ip)) = (&self.server_name, host_ip(base_url)) {
+            if url.set_host(Some(name)).is_ok() {
+                builder = builder.resolve(name, SocketAddr::new(ip, 0));
+            }
+        }
+
+        (builder.build().expect("reqwest HTTP client"), url)
+    }
+}
+
+/// Get the IP address of the host of `url`, if it is given by address.
+fn host_ip(url: &Url) -> Option<IpAddr> {
+    let host = url.host_str()?;
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use http::Method;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::OriginPool;
+
+    /// An origin for `origin.test` with a certificate from a private CA, which requires a
+    /// client certificate from the same CA, and responds with the `host` header it was sent.
+    struct TestOrigin {
+        addr: SocketAddr,
+        ca: String,
+        client_cert: String,
+        client_key: String,
+    }
+
+    /// Certificate parameters for `name`, which is both the common name and subject
+    /// alternative name, so that certificates are not mistaken for their issuer.
+    fn params(name: &str) -> CertificateParams {
+        let mut params = CertificateParams::new(vec![name.to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params
+    }
+
+    async fn test_origin() -> TestOrigin {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = params("Test CA");
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server = params("origin.test")
+            .signed_by(&server_key, &ca, &ca_key)
+            .unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let client = params("client.test")
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+
+        let provider = Arc::new(ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider.clone())
+            .build()
+            .unwrap();
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                vec![server.der().clone()],
+                PrivateKeyDer::Pkcs8(server_key.serialize_der().into()),
+            )
+            .unwrap();
+
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut buf = [0; 1024];
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+
+                let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                let host = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("host: "))
+                    .unwrap_or_default();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{host}",
+                    host.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        TestOrigin {
+            addr,
+            ca: ca.pem(),
+            client_cert: client.pem(),
+            client_key: client_key.serialize_pem(),
+        }
+    }
+
+    /// Send a request to the only origin of a pool, and return the body of the response.
+    async fn fetch(base_url: &str, options: &ClientOptions) -> reqwest::Result<String> {
+        let pool = OriginPool::new(vec![base_url.parse().unwrap()]).with_client_options(options);
+        let origin = Arc::new(pool).select(&[]).unwrap();
+
+        origin.request(Method::GET, "/a").send().await?.text().await
+    }
+
+    #[tokio::test]
+    async fn test_server_name() {
+        let origin = test_origin().await;
+        let options = ClientOptions::new()
+            .with_root_certificates(origin.ca.as_bytes())
+            .unwrap()
+            .with_identity(origin.client_cert.as_bytes(), origin.client_key.as_bytes())
+            .unwrap()
+            .with_server_name("origin.test");
+
+        let port = origin.addr.port();
+        let host = fetch(&format!("https://{}", origin.addr), &options).await;
+        assert_eq!(host.unwrap(), format!("origin.test:{port}"));
+
+        let (_, url) = options.build(&"https://[::1]/".parse().unwrap());
+        assert_eq!(url.as_str(), "https://origin.test/");
+
+        // Origins given by hostname are unaffected.
+        let (_, url) = options.build(&"https://other.test/".parse().unwrap());
+        assert_eq!(url.as_str(), "https://other.test/");
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let origin = test_origin().await;
+        let options = ClientOptions::new()
+            .with_root_certificates(origin.ca.as_bytes())
+            .unwrap()
+            .with_identity(origin.client_cert.as_bytes(), origin.client_key.as_bytes())
+            .unwrap()
+            .with_resolve("origin.test", SocketAddr::new(origin.addr.ip(), 0));
+
+        let port = origin.addr.port();
+        let host = fetch(&format!("https://origin.test:{port}"), &options).await;
+        assert_eq!(host.unwrap(), format!("origin.test:{port}"));
+    }
+
+    #[tokio::test]
+    async fn test_untrusted() {
+        let origin = test_origin().await;
+        let base_url = format!("https://origin.test:{}", origin.addr.port());
+        let options = ClientOptions::new().with_resolve("origin.test", origin.addr);
+
+        // Without the CA, the origin certificate is not trusted.
+        assert!(fetch(&base_url, &options).await.is_err());
+
+        // Without a client certificate, the origin refuses the connection.
+        let options = options
+            .with_root_certificates(origin.ca.as_bytes())
+            .unwrap();
+        assert!(fetch(&base_url, &options).await.is_err());
+    }
+
+    #[test]
+    fn test_invalid_pem() {
+        assert!(ClientOptions::new().with_identity(b"", b"").is_err());
+    }
+}
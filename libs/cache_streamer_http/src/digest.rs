@@ -0,0 +1,290 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use bytes::Bytes;
+use headers::{ETag, HeaderMap};
+use http::header::HeaderName;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+/// The `repr-digest` header, a structured dictionary of digests of the representation.
+const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+
+/// The legacy `digest` header from RFC 3230.
+const DIGEST: HeaderName = HeaderName::from_static("digest");
+
+/// The prefix of the S3 checksum headers, such as `x-amz-checksum-sha256`.
+const AMZ_CHECKSUM_PREFIX: &str = "x-amz-checksum-";
+
+/// A digest algorithm which the cache can verify.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+    Crc32,
+    Crc32c,
+}
+
+impl Algorithm {
+    /// Get the algorithm with the given name, as used by `repr-digest` and `digest`.
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha" => Some(Self::Sha1),
+            "sha-256" => Some(Self::Sha256),
+            "sha-512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Get the algorithm of an S3 checksum header with the given suffix.
+    fn from_amz_suffix(suffix: &str) -> Option<Self> {
+        match suffix {
+            "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            "crc32" => Some(Self::Crc32),
+            "crc32c" => Some(Self::Crc32c),
+            _ => None,
+        }
+    }
+
+    /// Compute the digest of a body split into chunks.
+    fn digest(self, body: &[Bytes]) -> Vec<u8> {
+        match self {
+            Self::Sha1 => hash::<Sha1>(body),
+            Self::Sha256 => hash::<Sha256>(body),
+            Self::Sha512 => hash::<Sha512>(body),
+            Self::Crc32 => {
+                let mut hasher = crc32fast::Hasher::new();
+                body.iter().for_each(|chunk| hasher.update(chunk));
+                hasher.finalize().to_be_bytes().to_vec()
+            }
+            Self::Crc32c => body
+                .iter()
+                .fold(0, |crc, chunk| crc32c::crc32c_append(crc, chunk))
+                .to_be_bytes()
+                .to_vec(),
+        }
+    }
+}
+
+/// Get the digests of the whole body declared by the `repr-digest`, `digest`, and
+/// `x-amz-checksum-*` response headers.
+///
+/// Digests with unknown algorithms or values which cannot be decoded, such as S3
+/// composite checksums of multipart uploads, are ignored.
+fn get_digests(response_headers: &HeaderMap) -> Vec<(Algorithm, Vec<u8>)> {
+    let mut digests = Vec::new();
+
+    // Dictionary members are `name=:base64:`, possibly followed by parameters.
+    for member in header_members(response_headers, &REPR_DIGEST) {
+        let Some((name, value)) = member.split_once('=') else {
+            continue;
+        };
+        let value = value.split(';').next().unwrap_or_default().trim();
+
+        if let (Some(algorithm), Some(value)) = (
+            Algorithm::from_name(name.trim()),
+            value.strip_prefix(':').and_then(|v| v.strip_suffix(':')),
+        ) {
+            digests.extend(decode(value).map(|value| (algorithm, value)));
+        }
+    }
+
+    // Members are `name=base64`, where the value may end with padding.
+    for member in header_members(response_headers, &DIGEST) {
+        let Some((name, value)) = member.split_once('=') else {
+            continue;
+        };
+
+        if let Some(algorithm) = Algorithm::from_name(name.trim()) {
+            digests.extend(decode(value.trim()).map(|value| (algorithm, value)));
+        }
+    }
+
+    for (name, value) in response_headers {
+        let algorithm = name
+            .as_str()
+            .strip_prefix(AMZ_CHECKSUM_PREFIX)
+            .and_then(Algorithm::from_amz_suffix);
+
+        if let (Some(algorithm), Ok(value)) = (algorithm, value.to_str()) {
+            digests.extend(decode(value.trim()).map(|value| (algorithm, value)));
+        }
+    }
+
+    digests
+}
+
+/// Verify a whole body split into chunks against the digests declared by the response
+/// headers. Returns `true` if there are no digests which can be verified.
+pub fn verify_body(response_headers: &HeaderMap, body: &[Bytes]) -> bool {
+    let digests = get_digests(response_headers);
+    let mut computed: Vec<(Algorithm, Vec<u8>)> = Vec::new();
+
+    for (algorithm, expected) in digests {
+        let actual = match computed.iter().find(|(a, _)| *a == algorithm) {
+            Some((_, actual)) => actual,
+            None => {
+                computed.push((algorithm, algorithm.digest(body)));
+                &computed.last().unwrap().1
+            }
+        };
+
+        if *actual != expected {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Generate a strong `etag` for a whole body split into chunks, from its SHA-256 digest.
+///
+/// The body must have been verified against the digests declared by the response
+/// headers; see [`verify_body`]. A declared SHA-256 digest is then used as is, rather
+/// than hashing the body again.
+pub fn body_etag(response_headers: &HeaderMap, body: &[Bytes]) -> ETag {
+    let declared = get_digests(response_headers)
+        .into_iter()
+        .find_map(|(algorithm, digest)| (algorithm == Algorithm::Sha256).then_some(digest));
+    let digest = URL_SAFE_NO_PAD.encode(declared.unwrap_or_else(|| hash::<Sha256>(body)));
+
+    // URL-safe base64 characters are all valid in entity tags.
+    format!("\"{digest}\"")
+        .parse()
+        .expect("entity tag is valid")
+}
+
+/// Returns whether the header with the given name may declare a digest of the whole body.
+pub fn is_digest_header(name: &HeaderName) -> bool {
+    name == REPR_DIGEST || name == DIGEST || name.as_str().starts_with(AMZ_CHECKSUM_PREFIX)
+}
+
+/// Get the comma-separated members of all values of the given header.
+fn header_members<'a>(
+    headers: &'a HeaderMap,
+    name: &HeaderName,
+) -> impl Iterator<Item = &'a str> + 'a {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+fn decode(value: &str) -> Option<Vec<u8>> {
+    STANDARD.decode(value).ok()
+}
+
+fn hash<D: sha2::Digest>(body: &[Bytes]) -> Vec<u8> {
+    let mut hasher = D::new();
+    body.iter().for_each(|chunk| hasher.update(chunk));
+    hasher.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    /// The body `hello world`, split into two chunks.
+    fn body() -> Vec<Bytes> {
+        vec![Bytes::from_static(b"hello "), Bytes::from_static(b"world")]
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+
+        headers
+    }
+
+    const SHA256: &str = "uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=";
+
+    #[test]
+    fn test_body_etag() {
+        let etag = body_etag(&HeaderMap::new(), &body());
+        assert_eq!(
+            etag,
+            "\"uU0nuZNNPgilLlLX2n2r-sSE7-N6U4DukIj3rOLvzek\""
+                .parse()
+                .unwrap()
+        );
+        let hello_world = [Bytes::from_static(b"hello world")];
+        assert_eq!(etag, body_etag(&HeaderMap::new(), &hello_world));
+        let hello = [Bytes::from_static(b"hello")];
+        assert_ne!(etag, body_etag(&HeaderMap::new(), &hello));
+
+        // A declared SHA-256 digest of the verified body gives the same tag.
+        let declared = headers(&[("x-amz-checksum-sha256", SHA256)]);
+        assert_eq!(etag, body_etag(&declared, &body()));
+        assert_eq!(etag, body_etag(&declared, &[]));
+    }
+
+    #[test]
+    fn test_no_digests() {
+        assert!(verify_body(&HeaderMap::new(), &body()));
+    }
+
+    #[test]
+    fn test_repr_digest() {
+        let valid = headers(&[(
+            "repr-digest",
+            "sha-256=:uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=:",
+        )]);
+        assert!(verify_body(&valid, &body()));
+        assert!(!verify_body(&valid, &[Bytes::from_static(b"hello")]));
+
+        // Unknown algorithms are ignored, but every known algorithm must match.
+        let valid = headers(&[(
+            "repr-digest",
+            "md5=:XrY7u+Ae7tCTyyK7j1rNww==:, sha-256=:uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=:",
+        )]);
+        assert!(verify_body(&valid, &body()));
+
+        let invalid = headers(&[(
+            "repr-digest",
+            "sha-256=:uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=:, sha-512=:AAAA:",
+        )]);
+        assert!(!verify_body(&invalid, &body()));
+    }
+
+    #[test]
+    fn test_digest() {
+        let valid = headers(&[(
+            "digest",
+            "SHA-256=uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=",
+        )]);
+        assert!(verify_body(&valid, &body()));
+
+        let valid = headers(&[("digest", "sha=Kq5sNclPz7QV2+lfQIuc6R7oRu0=")]);
+        assert!(verify_body(&valid, &body()));
+
+        let invalid = headers(&[("digest", "SHA-256=Kq5sNclPz7QV2+lfQIuc6R7oRu0=")]);
+        assert!(!verify_body(&invalid, &body()));
+    }
+
+    #[test]
+    fn test_amz_checksum() {
+        let valid = headers(&[
+            ("x-amz-checksum-sha256", SHA256),
+            ("x-amz-checksum-sha1", "Kq5sNclPz7QV2+lfQIuc6R7oRu0="),
+            ("x-amz-checksum-crc32", "DUoRhQ=="),
+            ("x-amz-checksum-crc32c", "yZRlqg=="),
+            ("x-amz-checksum-type", "FULL_OBJECT"),
+        ]);
+        assert!(verify_body(&valid, &body()));
+
+        // Composite checksums of multipart uploads are not checksums of the whole body.
+        let composite = headers(&[("x-amz-checksum-crc32", "AAAAAA==-2")]);
+        assert!(verify_body(&composite, &body()));
+
+        let invalid = headers(&[("x-amz-checksum-crc32c", "DUoRhQ==")]);
+        assert!(!verify_body(&invalid, &body()));
+    }
+}
@@ -1,29 +1,113 @@
 use headers::{
-    CacheControl, ContentDisposition, ContentLength, ContentRange, ContentType, Header, HeaderMap,
-    HeaderMapExt,
+    CacheControl, ContentDisposition, ContentLength, ContentRange, ContentType, ETag, Header,
+    HeaderMap, HeaderMapExt,
 };
+use http::header::{self, HeaderName};
+
+use crate::digest;
+
+/// Headers which only apply to a single connection, and must not be forwarded by proxies.
+const HOP_BY_HOP_HEADERS: [HeaderName; 9] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    header::HOST,
+];
 
 /// Take headers which will be preserved during requests.
 /// Currently, this list of headers is:
 /// - `cache-control`
 /// - `content-disposition`
+/// - `content-encoding`
 /// - `content-length`
 /// - `content-range`
 /// - `content-type`
+/// - `etag`
+/// - `vary`
+/// - `repr-digest`, `digest`, and `x-amz-checksum-*`, declaring digests of the body
 pub fn collect_headers(response_headers: &HeaderMap) -> HeaderMap {
     let mut headers = HeaderMap::new();
 
     clone_header::<CacheControl>(&mut headers, response_headers);
     clone_header::<ContentDisposition>(&mut headers, response_headers);
+    clone_raw_header(&mut headers, response_headers, header::CONTENT_ENCODING);
     clone_header::<ContentLength>(&mut headers, response_headers);
     clone_header::<ContentRange>(&mut headers, response_headers);
     clone_header::<ContentType>(&mut headers, response_headers);
+    clone_header::<ETag>(&mut headers, response_headers);
+    clone_raw_header(&mut headers, response_headers, header::VARY);
+
+    for name in response_headers
+        .keys()
+        .filter(|name| digest::is_digest_header(name))
+    {
+        clone_raw_header(&mut headers, response_headers, name.clone());
+    }
+
+    headers
+}
+
+/// Take all headers which can be forwarded by a proxy.
+///
+/// This removes the hop-by-hop headers defined in RFC 9110 section 7.6.1, as well as any
+/// headers named by the `connection` header. The `host` header is also removed, since it is
+/// set from the upstream URL.
+pub fn end_to_end_headers(headers: &HeaderMap) -> HeaderMap {
+    let connection_headers: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+
+    let mut headers = headers.clone();
+
+    for name in HOP_BY_HOP_HEADERS.iter().chain(&connection_headers) {
+        headers.remove(name);
+    }
+
+    headers
+}
+
+/// Copy the headers named in `forwarded` from a client request with `request_headers`.
+pub fn forwarded_headers(request_headers: &HeaderMap, forwarded: &[HeaderName]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    for name in forwarded {
+        for value in request_headers.get_all(name) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
 
     headers
 }
 
+/// Add the `injected` headers to `headers`, replacing any existing headers of the same name.
+pub fn inject_headers(headers: &mut HeaderMap, injected: &HeaderMap) {
+    for name in injected.keys() {
+        headers.remove(name);
+    }
+
+    for (name, value) in injected {
+        headers.append(name.clone(), value.clone());
+    }
+}
+
 fn clone_header<H: Header>(dest: &mut HeaderMap, src: &HeaderMap) {
     if let Some(header) = src.typed_get::<H>() {
         dest.typed_insert(header);
     }
 }
+
+/// Clone all values of the header with the given name without parsing them.
+fn clone_raw_header(dest: &mut HeaderMap, src: &HeaderMap, name: HeaderName) {
+    for value in src.get_all(&name) {
+        dest.append(name.clone(), value.clone());
+    }
+}
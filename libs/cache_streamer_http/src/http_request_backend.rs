@@ -1,53 +1,212 @@
 use std::sync::Arc;
 use std::time::Duration;
 
+use bytes::Bytes;
 use cache_streamer_lib::types::*;
-use reqwest::{Client, Url};
+use chrono::TimeDelta;
+use futures::{Stream, StreamExt};
+use http::header::{self, HeaderName};
+use http::{HeaderMap, Method};
+use reqwest::Body;
 
-use crate::http_requester::HTTPRequester;
+use crate::header_util;
+use crate::http_requester::{no_origins, upstream_error, HTTPRequester};
 use crate::http_response::HTTPResponse;
+use crate::origin_pool::OriginPool;
 
 /// [`RequestBackend`] trait implementation for HTTP.
 ///
-/// Creates HTTP [`Requester`] objects which fetch paths via [`reqwest`].
+/// Creates HTTP [`Requester`] objects which fetch paths from a pool of origins via
+/// [`reqwest`].
+///
+/// The state of the origins, such as their health and whether they ignore ranges, is
+/// shared between all requesters.
 pub struct HTTPRequestBackend {
-    client: Arc<Client>,
-    base_url: Url,
+    origins: Arc<OriginPool>,
     cache_limit: usize,
+    forwarded_headers: Vec<HeaderName>,
+    injected_headers: HeaderMap,
+    private_headers: Vec<HeaderName>,
+    negative: Option<(TimeDelta, usize)>,
+    default_ttl: Option<TimeDelta>,
+    max_ttl: Option<TimeDelta>,
+    timeouts: (Option<Duration>, Option<Duration>, Option<Duration>),
 }
 
 impl HTTPRequestBackend {
     /// Create a new [`HTTPRequestBackend`].
     ///
-    /// `base_url` fixes the scheme, host and port.
+    /// `origins` fix the scheme, host and port, and serve identical content.
     /// The request path is controlled by the key set in [`RequestBackend::create_for_key`].
     ///
     /// `cache_limit` controls the maximum length of responses able to be cached. Responses
     /// above this length will be passed through instead.
-    pub fn new(base_url: Url, cache_limit: usize) -> Self {
-        let client = Client::builder()
-            .redirect(reqwest::redirect::Policy::limited(1))
-            .connect_timeout(Duration::from_secs(10))
-            .http2_adaptive_window(true)
-            .build()
-            .expect("reqwest HTTP client");
-
+    ///
+    /// By default, no client request headers are forwarded upstream, and `authorization`
+    /// and `cookie` are private headers; see [`HTTPRequestBackend::with_private_headers`].
+    pub fn new(origins: OriginPool, cache_limit: usize) -> Self {
         Self {
-            client: Arc::new(client),
-            base_url,
+            origins: Arc::new(origins),
             cache_limit,
+            forwarded_headers: Vec::new(),
+            injected_headers: HeaderMap::new(),
+            private_headers: vec![header::AUTHORIZATION, header::COOKIE],
+            negative: None,
+            default_ttl: None,
+            max_ttl: None,
+            timeouts: Default::default(),
         }
     }
+
+    /// Forward the client request headers with the given names to the upstream server.
+    ///
+    /// Headers are taken from the request which caused the response to be fetched. Since
+    /// cached responses are shared between clients, only the forwarded headers which the
+    /// response varies on are sent when fetching missing bytes later, and responses which
+    /// do not vary on every forwarded header are only cached if they could be shared with
+    /// credentials; see [`HTTPRequester::with_headers`].
+    pub fn with_forwarded_headers(mut self, names: Vec<HeaderName>) -> Self {
+        self.forwarded_headers = names;
+        self
+    }
+
+    /// Send the given headers with every request to the upstream server, replacing any
+    /// forwarded headers with the same name.
+    ///
+    /// Requests for cacheable responses always use the `identity` encoding, so any
+    /// forwarded or injected `accept-encoding` header is replaced.
+    pub fn with_injected_headers(mut self, headers: HeaderMap) -> Self {
+        self.injected_headers = headers;
+        self
+    }
+
+    /// Set the forwarded headers which identify a client. When any of them are forwarded,
+    /// the response is only cached if `cache-control` allows sharing it, such as with `public`.
+    pub fn with_private_headers(mut self, names: Vec<HeaderName>) -> Self {
+        self.private_headers = names;
+        self
+    }
+
+    /// Cache `404 Not Found` and `410 Gone` responses for at most `ttl`, so that repeated
+    /// requests for missing objects do not reach the upstream server. Only responses of at
+    /// most `cache_limit` bytes are cached.
+    ///
+    /// By default, these responses are passed through.
+    pub fn with_negative_caching(mut self, ttl: TimeDelta, cache_limit: usize) -> Self {
+        self.negative = Some((ttl, cache_limit));
+        self
+    }
+
+    /// Cache responses without a freshness lifetime, which would otherwise never expire,
+    /// for `ttl`.
+    pub fn with_default_ttl(mut self, ttl: TimeDelta) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Cache responses for at most `ttl`, regardless of their freshness lifetime.
+    pub fn with_max_ttl(mut self, ttl: TimeDelta) -> Self {
+        self.max_ttl = Some(ttl);
+        self
+    }
+
+    /// Limit how long requests for cacheable responses may take; see
+    /// [`HTTPRequester::with_timeouts`].
+    pub fn with_timeouts(
+        mut self,
+        first_byte: Option<Duration>,
+        idle: Option<Duration>,
+        total: Option<Duration>,
+    ) -> Self {
+        self.timeouts = (first_byte, idle, total);
+        self
+    }
+}
+
+impl HTTPRequestBackend {
+    /// Send a request with the given method, query string, headers and body for the path
+    /// `key` directly to the upstream server, and return its response without caching it.
+    ///
+    /// Hop-by-hop headers are removed from both the request and the response, and injected
+    /// headers are added to the request. Since the request body cannot be replayed, the
+    /// request is not retried on other origins.
+    pub async fn passthrough<B, E>(
+        &self,
+        method: &Method,
+        key: &str,
+        query: Option<&str>,
+        headers: &HeaderMap,
+        body: B,
+    ) -> Result<HTTPResponse>
+    where
+        B: Stream<Item = std::result::Result<Bytes, E>> + Send + 'static,
+        E: Into<BoxError>,
+    {
+        let origin = self.origins.select(&[]).ok_or_else(no_origins)?;
+
+        let mut headers = header_util::end_to_end_headers(headers);
+        header_util::inject_headers(&mut headers, &self.injected_headers);
+
+        let response = origin
+            .request_with_query(method.clone(), key, query)
+            .headers(headers)
+            .body(Body::wrap_stream(body))
+            .send()
+            .await
+            .map_err(upstream_error);
+
+        let response = match response {
+            Ok(response) if response.status().is_server_error() => {
+                origin.report_failure();
+                response
+            }
+            Ok(response) => {
+                origin.report_success();
+                response
+            }
+            Err(e) => {
+                if matches!(e, Error::UpstreamConnect(..)) {
+                    origin.report_failure();
+                }
+
+                return Err(e);
+            }
+        };
+
+        let status = response.status();
+        let headers = header_util::end_to_end_headers(response.headers());
+        let body = Box::pin(response.bytes_stream().map(move |r| {
+            // Hold the connection to the origin until the body is dropped.
+            let _ = &origin;
+            r.map_err(upstream_error)
+        }));
+
+        Ok(HTTPResponse::new(status, headers, body))
+    }
 }
 
 impl RequestBackend<String, HTTPResponse> for HTTPRequestBackend {
-    fn create_for_key(&self, key: &String) -> Arc<dyn Requester<HTTPResponse>> {
+    fn create_for_key(
+        &self,
+        key: &String,
+        request: &HeaderMap,
+    ) -> Arc<dyn Requester<HTTPResponse>> {
         let cache_limit = self.cache_limit;
-        let client = self.client.clone();
 
-        let mut url = self.base_url.clone();
-        url.set_path(key);
+        let forwarded = header_util::forwarded_headers(request, &self.forwarded_headers);
+        let private = self
+            .private_headers
+            .iter()
+            .any(|name| self.forwarded_headers.contains(name) && request.contains_key(name));
 
-        Arc::new(HTTPRequester::new(client, url, cache_limit))
+        let requester = HTTPRequester::new(self.origins.clone(), key, cache_limit)
+            .with_headers(forwarded, self.injected_headers.clone(), private)
+            .with_ttl_overrides(self.default_ttl, self.max_ttl)
+            .with_timeouts(self.timeouts.0, self.timeouts.1, self.timeouts.2);
+
+        match self.negative {
+            Some((ttl, cache_limit)) => Arc::new(requester.with_negative_caching(ttl, cache_limit)),
+            None => Arc::new(requester),
+        }
     }
 }
@@ -1,37 +1,184 @@
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 use cache_streamer_lib::types::*;
-use futures::{future, StreamExt};
-use reqwest::{Client, Response as ReqwestResponse, Url};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{stream, Stream, StreamExt};
+use http::header::{self, HeaderName, HeaderValue};
+use http::{HeaderMap, Method, StatusCode};
+use reqwest::{RequestBuilder, Response as ReqwestResponse};
 
 use crate::http_response::HTTPResponse;
+use crate::origin_pool::{Connection, OriginPool};
 use crate::{header_util, parse, render};
 
 /// [`Requester`] trait implementation for HTTP.
 ///
-/// Makes HTTP requests against a fixed [`Url`] via [`reqwest`].
+/// Makes HTTP requests for a fixed path against the origins of an [`OriginPool`] via
+/// [`reqwest`]. If an origin cannot be reached, the request is retried on the other
+/// origins, since they serve identical bytes.
+///
+/// Requests ask for the `identity` encoding, so that byte ranges of cached responses
+/// always refer to the same representation.
+///
+/// If an origin returns the whole body with `200 OK` when a range is requested, it is
+/// assumed to ignore ranges, and later requests to it ask for the whole body instead.
+///
+/// Requests which exceed their timeouts fail with [`Error::UpstreamTimeout`], so that
+/// stalled bodies are fetched again from the offset they reached.
 pub struct HTTPRequester {
-    client: Arc<Client>,
-    url: Url,
+    origins: Arc<OriginPool>,
+    path: String,
+    forwarded: HeaderMap,
+    injected: HeaderMap,
+    sent: AtomicBool,
+    policy: CachePolicy,
+    timeouts: Timeouts,
+    encoding: Arc<OnceLock<Option<HeaderValue>>>,
+    vary: Arc<OnceLock<Vec<HeaderName>>>,
+}
+
+/// Limits on how long requests to the upstream server may take.
+#[derive(Clone, Copy, Default)]
+struct Timeouts {
+    first_byte: Option<Duration>,
+    idle: Option<Duration>,
+    total: Option<Duration>,
+}
+
+/// Options which control whether responses are cached.
+#[derive(Clone, Copy)]
+struct CachePolicy {
     cache_limit: usize,
+    private: bool,
+    negative_ttl: Option<TimeDelta>,
+    negative_limit: usize,
+    default_ttl: Option<TimeDelta>,
+    max_ttl: Option<TimeDelta>,
 }
 
 impl HTTPRequester {
-    /// Builds a new [`HTTPRequester`] using the shared [`Client`].
-    ///
-    /// `url` will be used without changes to make all requests.
+    /// Builds a new [`HTTPRequester`] for `path` on the `origins`, through the client of
+    /// each origin.
     ///
     /// A response will switch to [`RequesterStatus::Passthrough`] if the response
     /// would have otherwise been cached, but the length is more than `cache_limit`.
-    pub fn new(client: Arc<Client>, url: Url, cache_limit: usize) -> Self {
+    pub fn new(origins: Arc<OriginPool>, path: &str, cache_limit: usize) -> Self {
         Self {
-            client,
-            url,
-            cache_limit,
+            origins,
+            path: path.to_owned(),
+            forwarded: HeaderMap::new(),
+            injected: HeaderMap::new(),
+            sent: AtomicBool::new(false),
+            policy: CachePolicy {
+                cache_limit,
+                private: false,
+                negative_ttl: None,
+                negative_limit: 0,
+                default_ttl: None,
+                max_ttl: None,
+            },
+            timeouts: Timeouts::default(),
+            encoding: Arc::default(),
+            vary: Arc::default(),
         }
     }
+
+    /// Send the client request headers `forwarded`, and the `injected` headers which replace
+    /// forwarded headers of the same name, alongside the `range` header.
+    ///
+    /// Only the first request, which is made for the client, sends every forwarded header.
+    /// Later requests, such as to fetch missing bytes, may be made for other clients, so they
+    /// only send the forwarded headers which the first cacheable response varies on.
+    ///
+    /// Forwarded headers may change the response, so responses which do not vary on all of
+    /// them are only cached when `cache-control` allows sharing them, such as with `public`.
+    /// If `private` is set, the headers identify the client, so this applies regardless.
+    pub fn with_headers(
+        mut self,
+        forwarded: HeaderMap,
+        injected: HeaderMap,
+        private: bool,
+    ) -> Self {
+        self.forwarded = forwarded;
+        self.injected = injected;
+        self.policy.private = private;
+        self
+    }
+
+    /// Cache `404 Not Found` and `410 Gone` responses for at most `ttl`, if their
+    /// length is at most `cache_limit`, which replaces the limit for other responses.
+    ///
+    /// These responses are served whole, with their original status, regardless of
+    /// the requested range.
+    pub fn with_negative_caching(mut self, ttl: TimeDelta, cache_limit: usize) -> Self {
+        self.policy.negative_ttl = Some(ttl);
+        self.policy.negative_limit = cache_limit;
+        self
+    }
+
+    /// Override the expiration times of cacheable responses. Responses without a freshness
+    /// lifetime expire after `default_ttl` instead of never expiring, and no response is
+    /// cached for longer than `max_ttl`.
+    pub fn with_ttl_overrides(
+        mut self,
+        default_ttl: Option<TimeDelta>,
+        max_ttl: Option<TimeDelta>,
+    ) -> Self {
+        self.policy.default_ttl = default_ttl;
+        self.policy.max_ttl = max_ttl;
+        self
+    }
+
+    /// Limit how long requests may take. Without a response within `first_byte`, the
+    /// request is retried on the other origins. Bodies fail if no bytes arrive for `idle`,
+    /// or if the whole request takes longer than `total`.
+    ///
+    /// By default, requests only time out while connecting.
+    pub fn with_timeouts(
+        mut self,
+        first_byte: Option<Duration>,
+        idle: Option<Duration>,
+        total: Option<Duration>,
+    ) -> Self {
+        self.timeouts = Timeouts {
+            first_byte,
+            idle,
+            total,
+        };
+        self
+    }
+
+    /// Get the headers to send with the next request, and the names of the forwarded
+    /// headers among them; see [`HTTPRequester::with_headers`].
+    fn request_headers(&self) -> (HeaderMap, Vec<HeaderName>) {
+        let first = !self.sent.swap(true, Ordering::Relaxed);
+        let vary = self.vary.get();
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.forwarded {
+            if first || vary.is_some_and(|vary| vary.contains(name)) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+
+        let forwarded = headers
+            .keys()
+            .filter(|name| !self.injected.contains_key(*name))
+            .cloned()
+            .collect();
+
+        header_util::inject_headers(&mut headers, &self.injected);
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("identity"),
+        );
+
+        (headers, forwarded)
+    }
 }
 
 impl Requester<HTTPResponse> for HTTPRequester {
@@ -39,60 +186,322 @@ impl Requester<HTTPResponse> for HTTPRequester {
         &self,
         range: &RequestRange,
     ) -> Pin<Box<dyn Future<Output = Result<RequesterStatus<HTTPResponse>>> + Send + Sync>> {
-        let limit = self.cache_limit;
+        let policy = self.policy;
+        let idle = self.timeouts.idle;
+        let encoding = self.encoding.clone();
+        let vary = self.vary.clone();
+        let (request, forwarded) = self.request(Method::GET, range);
 
-        let range = range.clone();
-        let headers = match render::request_range_headers(&range) {
-            Some(headers) => headers,
-            None => return Box::pin(future::ready(Err("invalid request range".into()))),
-        };
+        Box::pin(async move {
+            let (response, range, origin, times) = request.send().await?;
 
-        let req = self.client.get(self.url.clone()).headers(headers).send();
+            into_requester_status(response, range, policy, &forwarded, idle, times, origin)
+                .and_then(|status| check_encoding(status, &encoding))
+                .inspect(|status| record_vary(status, &vary))
+        })
+    }
+
+    /// Fetches the response with HTTP `HEAD`, so that no body is transferred.
+    fn fetch_metadata(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<RequesterStatus<HTTPResponse>>> + Send + Sync>> {
+        let policy = self.policy;
+        let idle = self.timeouts.idle;
+        let encoding = self.encoding.clone();
+        let vary = self.vary.clone();
+        let (request, forwarded) = self.request(Method::HEAD, &RequestRange::None);
 
         Box::pin(async move {
-            // Convert to response here to avoid unnecessarily tying lifetime to `self`
-            req.await
-                .map_err(|e| e.into())
-                .and_then(|r| into_requester_status(r, range, limit))
+            let (response, _, origin, times) = request.send().await?;
+            let range = RequestRange::None;
+
+            into_requester_status(response, range, policy, &forwarded, idle, times, origin)
+                .and_then(|status| check_encoding(status, &encoding))
+                .inspect(|status| record_vary(status, &vary))
         })
     }
 }
 
+/// A request for a range of a path, which is sent to the origins of a pool in turn until
+/// one of them responds.
+struct UpstreamRequest {
+    origins: Arc<OriginPool>,
+    path: String,
+    method: Method,
+    headers: HeaderMap,
+    range: RequestRange,
+    timeouts: Timeouts,
+}
+
+impl HTTPRequester {
+    /// Build a request for `range` with the given method, which is owned so that its
+    /// lifetime is not tied to `self`, along with the names of the forwarded headers it sends.
+    fn request(&self, method: Method, range: &RequestRange) -> (UpstreamRequest, Vec<HeaderName>) {
+        let (headers, forwarded) = self.request_headers();
+
+        let request = UpstreamRequest {
+            origins: self.origins.clone(),
+            path: self.path.clone(),
+            method,
+            headers,
+            range: range.clone(),
+            timeouts: self.timeouts,
+        };
+
+        (request, forwarded)
+    }
+}
+
+impl UpstreamRequest {
+    /// Send the request to the selected origin, trying the next origin if it cannot be
+    /// reached or responds with a server error (5xx), until an origin responds successfully
+    /// or all origins have been tried. If every origin failed and some responded, the last
+    /// server error is returned.
+    ///
+    /// Returns the response, the range which was requested from the responding origin,
+    /// the connection to that origin, and the times at which the request was sent and
+    /// the response was received.
+    async fn send(
+        self,
+    ) -> Result<(
+        ReqwestResponse,
+        RequestRange,
+        Connection,
+        (DateTime<Utc>, DateTime<Utc>),
+    )> {
+        let mut tried = Vec::new();
+        let mut error = None;
+        let mut server_error = None;
+
+        while let Some(origin) = self.origins.select(&tried) {
+            // Origins which ignore ranges return the whole body anyway.
+            let range = match origin.ignores_ranges().load(Ordering::Relaxed) {
+                true => RequestRange::None,
+                false => self.range.clone(),
+            };
+            let range_headers = render::request_range_headers(&range).ok_or(Error::InvalidRange)?;
+
+            let mut headers = self.headers.clone();
+            headers.extend(range_headers);
+
+            let mut request = origin
+                .request(self.method.clone(), &self.path)
+                .headers(headers);
+
+            if let Some(total) = self.timeouts.total {
+                request = request.timeout(total);
+            }
+
+            let request_time = Utc::now();
+            let response = send_within(request, self.timeouts.first_byte).await;
+
+            match response {
+                Ok(response) if response.status().is_server_error() => {
+                    origin.report_failure();
+
+                    tracing::warn!(
+                        origin = %origin.base_url(),
+                        status = %response.status(),
+                        "upstream server error"
+                    );
+                    tried.push(origin.index());
+                    server_error = Some((response, range, origin, (request_time, Utc::now())));
+                }
+                Ok(response) => {
+                    origin.report_success();
+                    return Ok((response, range, origin, (request_time, Utc::now())));
+                }
+                Err(e) => {
+                    if matches!(e, Error::UpstreamConnect(..)) {
+                        origin.report_failure();
+                    }
+
+                    tracing::warn!(
+                        origin = %origin.base_url(),
+                        error = %e,
+                        "upstream request failed"
+                    );
+                    tried.push(origin.index());
+                    error = Some(e);
+                }
+            }
+        }
+
+        match (server_error, error) {
+            (Some(response), _) => Ok(response),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(no_origins()),
+        }
+    }
+}
+
+/// Send a request, failing with [`Error::UpstreamTimeout`] if there is no response within
+/// `first_byte`.
+async fn send_within(
+    request: RequestBuilder,
+    first_byte: Option<Duration>,
+) -> Result<ReqwestResponse> {
+    let response = match first_byte {
+        Some(limit) => tokio::time::timeout(limit, request.send())
+            .await
+            .map_err(|_| Error::UpstreamTimeout(format!("no response within {limit:?}").into()))?,
+        None => request.send().await,
+    };
+
+    response.map_err(upstream_error)
+}
+
+/// Fail a body with [`Error::UpstreamTimeout`] if no bytes arrive for `idle` while it is
+/// being read, and end it after the error.
+fn with_idle_timeout<S>(body: S, idle: Duration) -> BodyStream
+where
+    S: Stream<Item = Result<bytes::Bytes>> + Send + Sync + Unpin + 'static,
+{
+    Box::pin(stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+
+        match tokio::time::timeout(idle, body.next()).await {
+            Ok(Some(result)) => Some((result, Some(body))),
+            Ok(None) => None,
+            Err(_) => {
+                let e = format!("no bytes received for {idle:?}");
+                Some((Err(Error::UpstreamTimeout(e.into())), None))
+            }
+        }
+    }))
+}
+
 /// Convert the response from [`reqwest`] into a suitable [`HTTPResponse`].
 ///
 /// The following conditions are required to ensure that the output status
 /// is [`RequesterStatus::Cache`]:
-/// * Response status is success (2xx)
-/// * Response range corresponds to request range
-/// * Response total length is less than `cache_limit`
-/// * Response `cache-control` header does not disallow caching
+/// * Response status is success (2xx), or is a negatively cached error status
+/// * Response range corresponds to request range, or is the whole body for errors, or
+///   for `200 OK` responses from servers which ignore ranges; see [`whole_body_range`]
+/// * Response total length is less than `cache_limit`, or the negative caching limit for
+///   error statuses
+/// * Response headers allow a shared cache to store it; see
+///   [`parse::get_cache_possible_and_expire_time`]
+/// * Response headers allow sharing it with other clients, if the request was `private`
+///   or the response does not vary on all of the `forwarded` request headers
+/// * Response `vary` header does not contain `*`
+///
+/// The body fails if no bytes arrive for `idle`; see [`with_idle_timeout`].
+///
+/// `times` are the times at which the request was sent and the response was received.
+/// The output headers contain the corrected `age` of the response when it was received.
+///
+/// The body holds the connection to `origin`, so that it counts as in flight until the
+/// body is dropped.
+///
+/// Responses which would otherwise be cached, but do not report a length, such as chunked
+/// responses, result in [`RequesterStatus::CacheUnknownLength`] when the whole body was
+/// requested, so that they are cached once their body has been read.
 ///
 /// Otherwise, [`RequesterStatus::Passthrough`] will be returned.
 fn into_requester_status(
     response: ReqwestResponse,
     request_range: RequestRange,
-    cache_limit: usize,
+    policy: CachePolicy,
+    forwarded: &[HeaderName],
+    idle: Option<Duration>,
+    (request_time, response_time): (DateTime<Utc>, DateTime<Utc>),
+    origin: Connection,
 ) -> Result<RequesterStatus<HTTPResponse>> {
     let status = response.status();
     let input_headers = response.headers();
 
     // Headers from the response determine which headers will be sent, the range to be sent,
     // and the cacheability and expiration time.
-    let output_headers = header_util::collect_headers(input_headers);
-    let response_range = parse::into_response_range(input_headers, &request_range);
-    let (cache, expire_time) = parse::get_cache_possible_and_expire_time(input_headers);
+    let mut output_headers = header_util::collect_headers(input_headers);
+    let age = parse::get_initial_age(input_headers, request_time, response_time);
+    render::put_age(&mut output_headers, age);
+    let (cache, expire_time) =
+        parse::get_cache_possible_and_expire_time(input_headers, request_time, response_time);
+
+    // Responses without a freshness lifetime expire after the default TTL, if any, and
+    // no response expires after the maximum TTL.
+    let expire_time = expire_time.or_else(|| {
+        let ttl = policy.default_ttl?;
+        response_time.checked_add_signed(ttl)
+    });
+    let max_expire_time = policy
+        .max_ttl
+        .and_then(|ttl| response_time.checked_add_signed(ttl));
+    let expire_time = [expire_time, max_expire_time].into_iter().flatten().min();
+
+    // Negatively cached error responses are stored whole, since they do not accept ranges,
+    // and expire after at most the negative TTL.
+    let negative_ttl = policy
+        .negative_ttl
+        .filter(|_| matches!(status, StatusCode::NOT_FOUND | StatusCode::GONE));
+    let (response_range, expire_time) = match negative_ttl {
+        Some(ttl) => (
+            parse::into_response_range(input_headers, &RequestRange::None),
+            [expire_time, response_time.checked_add_signed(ttl)]
+                .into_iter()
+                .flatten()
+                .min(),
+        ),
+        None => (
+            parse::into_response_range(input_headers, &request_range)
+                .or_else(|| whole_body_range(status, input_headers, &request_range, &origin)),
+            expire_time,
+        ),
+    };
+
+    // Responses to requests with credentials must not be shared unless explicitly allowed,
+    // and neither may responses to requests with forwarded headers they do not vary on,
+    // since they are cached for every value of those headers. Responses which vary on `*`
+    // can never be reused.
+    let vary = parse::get_vary_names(input_headers);
+    let private = policy.private
+        || vary
+            .as_ref()
+            .is_some_and(|vary| forwarded.iter().any(|name| !vary.contains(name)));
+    let cache = cache
+        && (!private || parse::is_shareable_with_authorization(input_headers))
+        && vary.is_some();
+
+    // Responses without any length can only be cached whole, once their length is known.
+    let unknown_length = !input_headers.contains_key(header::CONTENT_LENGTH)
+        && !input_headers.contains_key(header::CONTENT_RANGE)
+        && (matches!(request_range, RequestRange::None) || negative_ttl.is_some());
 
     // Get the body stream.
-    let body = Box::pin(response.bytes_stream().map(|r| r.map_err(|e| e.into())));
+    let body = Box::pin(response.bytes_stream().map(move |r| {
+        // Hold the connection to the origin until the body is dropped.
+        let _ = &origin;
+        r.map_err(upstream_error)
+    }));
+    let body = match idle {
+        Some(idle) => with_idle_timeout(body, idle),
+        None => body,
+    };
 
     // Don't report responses which do not report a length or are too large as cacheable.
+    // Error responses have a limit of their own, since their bodies should be small.
+    let cache_limit = match negative_ttl {
+        Some(_) => policy.negative_limit,
+        None => policy.cache_limit,
+    };
     let cacheable_total_size = response_range
         .as_ref()
         .map(|r| r.bytes_len <= cache_limit)
         .unwrap_or(false);
 
     // Check all preconditions.
-    if !status.is_success() || !cacheable_total_size || !cache {
+    let cacheable_status = status.is_success() || negative_ttl.is_some();
+    if cacheable_status && unknown_length && cache {
+        return Ok(RequesterStatus::CacheUnknownLength(
+            HTTPResponse::new(status, output_headers.clone(), body),
+            cache_limit,
+            expire_time,
+            (status, output_headers),
+        ));
+    }
+
+    if !cacheable_status || !cacheable_total_size || !cache {
         return Ok(RequesterStatus::Passthrough(HTTPResponse::new(
             status,
             output_headers,
@@ -117,3 +526,346 @@ fn into_requester_status(
         (status, output_headers),
     ))
 }
+
+/// Get the range of a `200 OK` response to a request for a range, which contains the
+/// whole body since the origin ignored the requested range. Records that the origin
+/// ignores ranges, so that later requests to it ask for the whole body.
+///
+/// Returns [`None`] for any other response, or if the response has no length.
+fn whole_body_range(
+    status: StatusCode,
+    headers: &HeaderMap,
+    request_range: &RequestRange,
+    origin: &Connection,
+) -> Option<ResponseRange> {
+    if status != StatusCode::OK
+        || matches!(request_range, RequestRange::None)
+        || headers.contains_key(header::CONTENT_RANGE)
+    {
+        return None;
+    }
+
+    let range = parse::into_response_range(headers, &RequestRange::None)?;
+
+    if !origin.ignores_ranges().swap(true, Ordering::Relaxed) {
+        tracing::info!(
+            origin = %origin.base_url(),
+            "upstream ignores range requests, so whole bodies will be requested"
+        );
+    }
+
+    Some(range)
+}
+
+/// Check that a cacheable response has the same `content-encoding` as the first cacheable
+/// response from the same requester, since cached byte ranges only apply within a single
+/// encoding. The origin may ignore the requested `identity` encoding.
+fn check_encoding(
+    status: RequesterStatus<HTTPResponse>,
+    encoding: &OnceLock<Option<HeaderValue>>,
+) -> Result<RequesterStatus<HTTPResponse>> {
+    if let RequesterStatus::Cache(_, _, _, (_, headers))
+    | RequesterStatus::CacheUnknownLength(_, _, _, (_, headers)) = &status
+    {
+        let current = headers.get(header::CONTENT_ENCODING);
+
+        if encoding.get_or_init(|| current.cloned()).as_ref() != current {
+            return Err(Error::CacheInconsistency(
+                "upstream content-encoding changed".into(),
+            ));
+        }
+    }
+
+    Ok(status)
+}
+
+/// Record the request headers which the first cacheable response from a requester varies
+/// on, which are forwarded with later requests.
+fn record_vary(status: &RequesterStatus<HTTPResponse>, vary: &OnceLock<Vec<HeaderName>>) {
+    if let RequesterStatus::Cache(_, _, _, (_, headers))
+    | RequesterStatus::CacheUnknownLength(_, _, _, (_, headers)) = status
+    {
+        vary.get_or_init(|| parse::get_vary_names(headers).unwrap_or_default());
+    }
+}
+
+/// The error for a request which could not be sent, since no origin could be selected.
+pub(crate) fn no_origins() -> Error {
+    Error::UpstreamConnect("no origins to send the request to".into())
+}
+
+/// Classify an error from [`reqwest`] into an [`Error`].
+pub(crate) fn upstream_error(error: reqwest::Error) -> Error {
+    if error.is_timeout() {
+        Error::UpstreamTimeout(error.into())
+    } else if error.is_connect() {
+        Error::UpstreamConnect(error.into())
+    } else {
+        Error::UpstreamProtocol(error.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// An origin which sends the first half of `hello world` and then stalls, unless the
+    /// rest of the body is requested. Requests for `/slow` are never answered, and
+    /// requests for `/missing` are answered with `404 Not Found`.
+    async fn stalling_origin() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut buf = [0; 1024];
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+
+                    let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                    let response: &[u8] = match request {
+                        r if r.starts_with("get /slow ") => b"",
+                        r if r.starts_with("get /missing ") => {
+                            b"HTTP/1.1 404 Not Found\r\ncontent-length: 9\r\n\r\nnot found"
+                        }
+                        r if r.contains("range: bytes=5-") => {
+                            b"HTTP/1.1 206 Partial Content\r\ncontent-range: bytes 5-10/11\r\n\
+                              content-length: 6\r\n\r\n world"
+                        }
+                        _ => b"HTTP/1.1 200 OK\r\ncontent-length: 11\r\n\r\nhello",
+                    };
+
+                    let _ = stream.write_all(response).await;
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    /// An origin which answers every request with `503 Service Unavailable`.
+    async fn unavailable_origin() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n")
+                    .await;
+            }
+        });
+
+        addr
+    }
+
+    /// An origin which answers every request with a cacheable `hello`, which varies on
+    /// `x-tenant` for requests for `/vary`, and is also public for requests for `/public`.
+    /// Records the requests it receives.
+    async fn recording_origin() -> (SocketAddr, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let recorded = recorded.clone();
+
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut buf = [0; 1024];
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+
+                    let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                    let headers = match &request {
+                        r if r.starts_with("get /vary ") => "vary: x-tenant\r\n",
+                        r if r.starts_with("get /public ") => {
+                            "vary: x-tenant\r\ncache-control: public\r\n"
+                        }
+                        _ => "",
+                    };
+                    recorded.lock().unwrap().push(request);
+
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\n{headers}\
+                         content-length: 5\r\n\r\nhello"
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        (addr, requests)
+    }
+
+    fn requester(addr: SocketAddr, path: &str) -> HTTPRequester {
+        pool_requester(&[addr], path)
+    }
+
+    fn pool_requester(addrs: &[SocketAddr], path: &str) -> HTTPRequester {
+        let urls = addrs
+            .iter()
+            .map(|addr| format!("http://{addr}").parse().unwrap())
+            .collect();
+        HTTPRequester::new(Arc::new(OriginPool::new(urls)), path, 1 << 20)
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let addr = stalling_origin().await;
+        let requester =
+            requester(addr, "/a").with_timeouts(None, Some(Duration::from_millis(100)), None);
+
+        let Ok(RequesterStatus::Cache(response, ..)) = requester.fetch(&RequestRange::None).await
+        else {
+            panic!("response is not cacheable");
+        };
+
+        let mut body = response.into_body();
+        assert_eq!(body.next().await.unwrap().unwrap().as_ref(), b"hello");
+        assert!(matches!(
+            body.next().await,
+            Some(Err(Error::UpstreamTimeout(..)))
+        ));
+        assert!(body.next().await.is_none());
+
+        // The rest of the body can be requested again from the offset it reached.
+        let Ok(RequesterStatus::Cache(response, ..)) =
+            requester.fetch(&RequestRange::AllFrom(5)).await
+        else {
+            panic!("response is not cacheable");
+        };
+
+        let mut body = response.into_body();
+        assert_eq!(body.next().await.unwrap().unwrap().as_ref(), b" world");
+    }
+
+    #[tokio::test]
+    async fn test_first_byte_timeout() {
+        let addr = stalling_origin().await;
+        let requester =
+            requester(addr, "/slow").with_timeouts(Some(Duration::from_millis(100)), None, None);
+
+        let result = requester.fetch(&RequestRange::None).await;
+        assert!(matches!(result, Err(Error::UpstreamTimeout(..))));
+    }
+
+    #[tokio::test]
+    async fn test_total_timeout() {
+        let addr = stalling_origin().await;
+        let requester =
+            requester(addr, "/a").with_timeouts(None, None, Some(Duration::from_millis(100)));
+
+        let Ok(RequesterStatus::Cache(response, ..)) = requester.fetch(&RequestRange::None).await
+        else {
+            panic!("response is not cacheable");
+        };
+
+        let mut body = response.into_body();
+        assert_eq!(body.next().await.unwrap().unwrap().as_ref(), b"hello");
+        assert!(matches!(
+            body.next().await,
+            Some(Err(Error::UpstreamTimeout(..)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_negative_cache_limit() {
+        let addr = stalling_origin().await;
+        let ttl = TimeDelta::seconds(60);
+
+        let cached = requester(addr, "/missing").with_negative_caching(ttl, 1024);
+        let status = cached.fetch(&RequestRange::None).await;
+        assert!(matches!(status, Ok(RequesterStatus::Cache(..))));
+
+        // Error responses larger than their own limit are passed through, even though
+        // they are within the limit for other responses.
+        let passed = requester(addr, "/missing").with_negative_caching(ttl, 4);
+        let status = passed.fetch(&RequestRange::None).await;
+        assert!(matches!(status, Ok(RequesterStatus::Passthrough(..))));
+    }
+
+    #[tokio::test]
+    async fn test_server_error_failover() {
+        let unavailable = unavailable_origin().await;
+        let available = stalling_origin().await;
+
+        // Server errors are retried on the next origin.
+        let requester = pool_requester(&[unavailable, available], "/a");
+        for _ in 0..2 {
+            let status = requester.fetch(&RequestRange::None).await;
+            assert!(matches!(status, Ok(RequesterStatus::Cache(..))));
+        }
+
+        // When every origin fails, the last server error is passed through.
+        let requester = pool_requester(&[unavailable, unavailable], "/a");
+        let Ok(RequesterStatus::Passthrough(response)) = requester.fetch(&RequestRange::None).await
+        else {
+            panic!("response is not passed through");
+        };
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_headers() {
+        let (addr, requests) = recording_origin().await;
+        let mut tenant = HeaderMap::new();
+        tenant.insert("x-tenant", HeaderValue::from_static("a"));
+        let mut forwarded = tenant.clone();
+        forwarded.insert("x-forwarded-for", HeaderValue::from_static("192.0.2.1"));
+        let mut injected = HeaderMap::new();
+        injected.insert("x-injected", HeaderValue::from_static("1"));
+
+        let public = requester(addr, "/public").with_headers(forwarded, injected.clone(), false);
+        for _ in 0..2 {
+            let status = public.fetch(&RequestRange::None).await;
+            assert!(matches!(status, Ok(RequesterStatus::Cache(..))));
+        }
+
+        // Later requests only forward the headers which the response varies on.
+        let requests = std::mem::take(&mut *requests.lock().unwrap());
+        assert!(requests[0].contains("x-tenant: a"));
+        assert!(requests[0].contains("x-forwarded-for: 192.0.2.1"));
+        assert!(requests[1].contains("x-tenant: a"));
+        assert!(!requests[1].contains("x-forwarded-for"));
+        assert!(requests.iter().all(|r| r.contains("x-injected: 1")));
+
+        // Responses which do not vary on every forwarded header are not shared otherwise.
+        let varying =
+            requester(addr, "/vary").with_headers(tenant.clone(), injected.clone(), false);
+        let status = varying.fetch(&RequestRange::None).await;
+        assert!(matches!(status, Ok(RequesterStatus::Cache(..))));
+
+        let shared = requester(addr, "/a").with_headers(tenant, injected.clone(), false);
+        let status = shared.fetch(&RequestRange::None).await;
+        assert!(matches!(status, Ok(RequesterStatus::Passthrough(..))));
+
+        let unforwarded = requester(addr, "/a").with_headers(HeaderMap::new(), injected, false);
+        let status = unforwarded.fetch(&RequestRange::None).await;
+        assert!(matches!(status, Ok(RequesterStatus::Cache(..))));
+    }
+}
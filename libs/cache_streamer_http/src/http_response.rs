@@ -1,8 +1,9 @@
-use crate::render;
+use crate::{digest, parse, render};
+use bytes::Bytes;
 use cache_streamer_lib::types::*;
 use chrono::{DateTime, Utc};
-use headers::HeaderMap;
-use http::StatusCode;
+use headers::{ETag, HeaderMap, HeaderMapExt};
+use http::{HeaderValue, StatusCode};
 
 /// [`Response`] trait implementation for HTTP.
 ///
@@ -33,6 +34,21 @@ impl HTTPResponse {
         (self.status, self.headers, self.body)
     }
 
+    /// Get the status of the response.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Get the headers of the response.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Get a mutable reference to the headers of the response.
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
     /// Override the status of the response.
     pub fn set_status(&mut self, status: StatusCode) {
         self.status = status;
@@ -47,6 +63,10 @@ impl HTTPResponse {
 impl Response for HTTPResponse {
     type Timepoint = DateTime<Utc>;
     type Data = (StatusCode, HeaderMap);
+    type RequestData = HeaderMap;
+
+    /// The names and values of the request headers listed by `vary`.
+    type VariantKey = Vec<(String, Vec<HeaderValue>)>;
 
     fn from_parts(
         (status, headers): Self::Data,
@@ -54,7 +74,7 @@ impl Response for HTTPResponse {
         body: BodyStream,
     ) -> Result<Self> {
         let headers = match render::put_response_range(headers, range) {
-            None => return Err("invalid response range".into()),
+            None => return Err(Error::InvalidRange),
             Some(headers) => headers,
         };
 
@@ -64,4 +84,56 @@ impl Response for HTTPResponse {
     fn into_body(self) -> BodyStream {
         self.body
     }
+
+    fn map_body<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(BodyStream) -> BodyStream,
+    {
+        self.body = f(self.body);
+        self
+    }
+
+    /// Only successful responses accept ranges, so that negatively cached error
+    /// responses are replayed whole.
+    fn accepts_ranges((status, _): &Self::Data) -> bool {
+        status.is_success()
+    }
+
+    /// Checked against the `repr-digest`, `digest`, and `x-amz-checksum-*` headers.
+    fn verify_body((_, headers): &Self::Data, body: &[Bytes]) -> bool {
+        digest::verify_body(headers, body)
+    }
+
+    /// Successful responses without an `etag` are given a strong one from the digest of
+    /// their body, so that clients can revalidate them. The body has been verified, so a
+    /// declared SHA-256 digest is used rather than hashing it again.
+    fn complete_data((status, headers): &Self::Data, body: &[Bytes]) -> Option<Self::Data> {
+        if !status.is_success() || headers.typed_get::<ETag>().is_some() {
+            return None;
+        }
+
+        let etag = digest::body_etag(headers, body);
+        let mut headers = headers.clone();
+        headers.typed_insert(etag);
+
+        Some((*status, headers))
+    }
+
+    fn variant_key((_, headers): &Self::Data, request: &HeaderMap) -> Option<Self::VariantKey> {
+        let names = parse::get_vary_names(headers)?;
+
+        if names.is_empty() {
+            return None;
+        }
+
+        let key = names
+            .into_iter()
+            .map(|name| {
+                let values = request.get_all(&name).iter().cloned().collect();
+                (name.as_str().to_owned(), values)
+            })
+            .collect();
+
+        Some(key)
+    }
 }
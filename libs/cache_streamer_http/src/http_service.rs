@@ -1,46 +1,165 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use bytes::Bytes;
-use cache_streamer_lib::types::{BodyStream, RequestBackend, RequestRange, ServiceStatus};
-use cache_streamer_lib::Service;
-use chrono::Utc;
-use futures::stream;
+use cache_streamer_lib::types::{
+    BodyStream, BoxError, CacheDetail, CacheStatus, Error, RequestRange, ServiceStatus,
+    StorageBackend,
+};
+use cache_streamer_lib::{ObjectReader, Service};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{stream, Stream};
+use headers::{Age, HeaderMapExt};
 use http::{HeaderMap, Method, StatusCode};
 
 use crate::http_response::HTTPResponse;
-use crate::parse::get_request_range;
+use crate::parse::{self, get_request_range};
+use crate::render;
+use crate::route_table::{RouteBackend, RouteKey, RouteTable, SharedRoutes};
 
 /// `cache_streamer` service implementation which makes HTTP requests and returns HTTP responses.
+///
+/// Requests are routed to backends by their hostname and path; see [`RouteTable`]. All
+/// routes share the same cache.
 pub struct HTTPService {
-    service: Service<String, HTTPResponse>,
+    service: Service<RouteKey, HTTPResponse>,
+    routes: SharedRoutes,
 }
 
 impl HTTPService {
-    /// Builds a new [`HTTPService`].
+    /// Builds a new [`HTTPService`] which fetches requests through the backends of `routes`.
     ///
     /// `cache_capacity` is the total size of the cache, such as 32GiB.
     ///
-    /// The maximum size of individual cacheable responses can be tuned when the
-    /// the `backend` parameter is a [`HTTPRequestBackend`].
-    pub fn new(
-        backend: Arc<dyn RequestBackend<String, HTTPResponse>>,
+    /// The maximum size of individual cacheable responses can be tuned for each route
+    /// when its backend is a [`HTTPRequestBackend`](crate::HTTPRequestBackend).
+    pub fn new(routes: RouteTable, cache_capacity: usize) -> Self {
+        let routes = Arc::new(RwLock::new(Arc::new(routes)));
+        let backend = RouteBackend::new(routes.clone());
+        let service = Service::new(Arc::new(backend), cache_capacity);
+
+        Self { service, routes }
+    }
+
+    /// Builds a new [`HTTPService`] which stores response bodies in storage created
+    /// by `storage`.
+    ///
+    /// See [`HTTPService::new`] for the other parameters.
+    pub fn with_storage(
+        routes: RouteTable,
+        storage: Arc<dyn StorageBackend>,
         cache_capacity: usize,
     ) -> Self {
-        let service = Service::new(backend, cache_capacity);
+        let routes = Arc::new(RwLock::new(Arc::new(routes)));
+        let backend = RouteBackend::new(routes.clone());
+        let service = Service::with_storage(Arc::new(backend), storage, cache_capacity);
+
+        Self { service, routes }
+    }
+
+    /// Set the maximum number of variants stored for each path, for responses which vary
+    /// on request headers listed by `vary`. When a new variant is stored, the oldest
+    /// variant is evicted.
+    pub fn with_max_variants(mut self, max_variants: usize) -> Self {
+        self.service = self.service.with_max_variants(max_variants);
+        self
+    }
+
+    /// Replace the routes of the service, such as when its configuration is reloaded.
+    ///
+    /// The cache is kept, so responses stored through a route with the same name as a new
+    /// route are served for it, and the missing parts of their bodies are fetched through
+    /// the new route. Requests which are in flight finish through the routes they started
+    /// with.
+    pub fn set_routes(&self, routes: RouteTable) {
+        *self.routes.write().unwrap() = Arc::new(routes);
+    }
+
+    /// Verify the stored bodies of all complete responses against the digests declared by
+    /// the upstream server, and evict responses which no longer match. Returns the number
+    /// of evicted responses.
+    ///
+    /// This reads every stored body, so it should be called from a blocking task.
+    pub fn scrub(&self) -> usize {
+        self.service.scrub()
+    }
+
+    /// Open a seekable reader over the complete response for `path`.
+    ///
+    /// `headers` are the client request headers, which select the route and may be
+    /// forwarded upstream. Returns [`None`] if no route matches, or if the response is
+    /// not cacheable.
+    pub async fn open(
+        &self,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> cache_streamer_lib::types::Result<Option<ObjectReader<HTTPResponse>>> {
+        let Some(key) = self.route_key(path, headers) else {
+            return Ok(None);
+        };
 
-        Self { service }
+        self.service.open(&Utc::now(), &key, headers).await
     }
 
     /// Fetch a [`HTTPResponse`] corresponding to the given request parameters.
     ///
     /// The output [`HTTPResponse`] is suitable for returning to a client.
-    /// All errors are internally handled.
-    pub async fn call(&self, method: &Method, key: &String, headers: &HeaderMap) -> HTTPResponse {
-        match fetch_into_status(&self.service, method, key, headers).await {
+    /// All errors are internally handled, and requests which match no route are answered
+    /// with [`StatusCode::NOT_FOUND`].
+    ///
+    /// `query` and `body` are only sent when the request is passed through to the upstream
+    /// server, since cached responses are identified by their path; see
+    /// [`Route::with_passthrough`](crate::Route::with_passthrough).
+    pub async fn call<B, E>(
+        &self,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        headers: &HeaderMap,
+        body: B,
+    ) -> HTTPResponse
+    where
+        B: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        E: Into<BoxError>,
+    {
+        let result = match self.route_key(path, headers) {
+            None => Err((StatusCode::NOT_FOUND, HeaderMap::new())),
+            Some(key) if matches!(*method, Method::GET | Method::HEAD) => {
+                fetch_into_status(&self.service, method, &key, headers).await
+            }
+            Some(key) => match key.route.passthrough() {
+                Some(backend) => {
+                    let result = backend
+                        .passthrough(method, path, query, headers, body)
+                        .await;
+
+                    result
+                        .map(|mut response| {
+                            render::put_cache_status(response.headers_mut(), "fwd=method");
+                            response
+                        })
+                        .map_err(|e| error_into_status(&e, method, path))
+                }
+                None => Err((StatusCode::METHOD_NOT_ALLOWED, HeaderMap::new())),
+            },
+        };
+
+        match result {
             Ok(response) => erase_body_if_head(response, method),
-            Err(status) => synthesize_response(status, method),
+            Err((status, headers)) => synthesize_response(status, headers, method),
         }
     }
+
+    /// Get the cache key of a request for `path` with the given headers, or [`None`] if
+    /// no route matches.
+    fn route_key(&self, path: &str, headers: &HeaderMap) -> Option<RouteKey> {
+        let routes = self.routes.read().unwrap().clone();
+        let route = routes.find(path, headers)?;
+
+        Some(RouteKey {
+            route: route.clone(),
+            path: path.to_owned(),
+        })
+    }
 }
 
 /// Create a [`BodyStream`] object that returns the contents of the input string.
@@ -59,8 +178,7 @@ fn erase_body_if_head(mut response: HTTPResponse, method: &Method) -> HTTPRespon
 
 /// Create a new [`HTTPResponse`] where the body is either the canonical name of the status
 /// code if the method is not HTTP `HEAD`, or empty if the method is HTTP `HEAD`.
-fn synthesize_response(status: StatusCode, method: &Method) -> HTTPResponse {
-    let headers = HeaderMap::new();
+fn synthesize_response(status: StatusCode, headers: HeaderMap, method: &Method) -> HTTPResponse {
     let body = if matches!(*method, Method::HEAD) {
         static_body("")
     } else {
@@ -71,40 +189,64 @@ fn synthesize_response(status: StatusCode, method: &Method) -> HTTPResponse {
 }
 
 /// Using the given [`Service`], fetch a [`HTTPResponse`] corresponding to the given request parameters
-/// or return a HTTP [`StatusCode`] indicating an error in processing.
+/// or return a HTTP [`StatusCode`] and headers indicating an error in processing.
+///
+/// The method must be HTTP `GET` or `HEAD`.
 ///
 /// Currently, the error status which will be returned are:
-/// * [`StatusCode::METHOD_NOT_ALLOWED`] when the method is not HTTP `GET` or `HEAD`
 /// * [`StatusCode::RANGE_NOT_SATISFIABLE`] when there is an issue with the input range
-/// * [`StatusCode::INTERNAL_SERVER_ERROR`] when connecting to the upstream server returns an error
+/// * Otherwise, the status corresponding to the error from the service call; see
+///   [`error_status`] and [`error_headers`]
 async fn fetch_into_status(
-    service: &Service<String, HTTPResponse>,
+    service: &Service<RouteKey, HTTPResponse>,
     method: &Method,
-    key: &String,
+    key: &RouteKey,
     headers: &HeaderMap,
-) -> Result<HTTPResponse, StatusCode> {
-    // We can't handle methods other than GET or HEAD.
-    if !matches!(*method, Method::GET | Method::HEAD) {
-        return Err(StatusCode::METHOD_NOT_ALLOWED);
-    }
-
-    let range = get_request_range(headers).ok_or(StatusCode::RANGE_NOT_SATISFIABLE)?;
+) -> Result<HTTPResponse, (StatusCode, HeaderMap)> {
+    let range =
+        get_request_range(headers).ok_or((StatusCode::RANGE_NOT_SATISFIABLE, HeaderMap::new()))?;
 
     // Fetch current time as close as possible to the service call.
     let timepoint = Utc::now();
 
-    // Map errors in the service call to HTTP 500.
-    let service_status = service
-        .call(&timepoint, key, &range)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Avoid fetching a body for HEAD requests, since it would be discarded.
+    let service_status = if matches!(*method, Method::HEAD) {
+        service
+            .call_metadata(&timepoint, key, &range, headers)
+            .await
+    } else {
+        service.call(&timepoint, key, &range, headers).await
+    };
+
+    let service_status = service_status.map_err(|e| error_into_status(&e, method, &key.path))?;
 
     // Return and don't post-process passed-through responses.
-    let mut response = match service_status {
-        ServiceStatus::Cache(r) => r,
-        ServiceStatus::Passthrough(r) => return Ok(r),
+    let (mut response, detail) = match service_status {
+        ServiceStatus::Cache(r, detail) => (r, detail),
+        ServiceStatus::Passthrough(mut r) => {
+            render::put_cache_status(r.headers_mut(), "fwd=uri-miss");
+            return Ok(r);
+        }
     };
 
+    put_cache_headers(response.headers_mut(), &detail, timepoint);
+
+    // Negatively cached error responses are replayed whole with their original status.
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+
+    // Conditions are evaluated before the range, against the stored `etag`, which may
+    // have been generated by the cache.
+    if parse::is_not_modified(headers, response.headers()) {
+        let headers = render::not_modified_headers(response.headers());
+        return Ok(HTTPResponse::new(
+            StatusCode::NOT_MODIFIED,
+            headers,
+            static_body(""),
+        ));
+    }
+
     // Handling the 204 No Content case is not required.
     // However, we must handle 206 Partial Content.
     if matches!(range, RequestRange::None) {
@@ -115,3 +257,222 @@ async fn fetch_into_status(
 
     Ok(response)
 }
+
+/// Add the `cache-status` and `age` headers to a response served from cache at `now`.
+///
+/// The response headers must contain the age of the response when it was inserted, which
+/// is increased by the time it has been in the cache.
+fn put_cache_headers(
+    headers: &mut HeaderMap,
+    detail: &CacheDetail<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) {
+    let params = match detail.status {
+        CacheStatus::Hit => "hit",
+        CacheStatus::PartialHit => "fwd=partial",
+        CacheStatus::Miss => "fwd=uri-miss; stored",
+    };
+
+    let initial_age = headers
+        .typed_get::<Age>()
+        .and_then(|age| TimeDelta::from_std(age.into()).ok())
+        .unwrap_or_default();
+    let age = initial_age
+        .checked_add(&(now - detail.insertion_time))
+        .unwrap_or(TimeDelta::MAX);
+
+    render::put_cache_status(headers, params);
+    render::put_age(headers, age);
+}
+
+/// Log an [`Error`] and convert it into the HTTP [`StatusCode`] and headers returned to the client.
+fn error_into_status(error: &Error, method: &Method, key: &str) -> (StatusCode, HeaderMap) {
+    let status = error_status(error);
+
+    if status.is_server_error() {
+        tracing::warn!(%method, key, %status, %error, "failed to fetch response");
+    } else {
+        tracing::debug!(%method, key, %status, %error, "failed to fetch response");
+    }
+
+    (status, error_headers(error))
+}
+
+/// Map an [`Error`] from the service to the HTTP [`StatusCode`] returned to the client.
+///
+/// * [`StatusCode::BAD_GATEWAY`] when the upstream server could not be reached or returned
+///   an invalid response
+/// * [`StatusCode::GATEWAY_TIMEOUT`] when the upstream server timed out
+/// * [`StatusCode::RANGE_NOT_SATISFIABLE`] when the range is invalid
+/// * [`StatusCode::INTERNAL_SERVER_ERROR`] for cache and storage errors
+fn error_status(error: &Error) -> StatusCode {
+    match error {
+        Error::UpstreamConnect(..) | Error::UpstreamProtocol(..) => StatusCode::BAD_GATEWAY,
+        Error::UpstreamTimeout(..) => StatusCode::GATEWAY_TIMEOUT,
+        Error::InvalidRange | Error::UnsatisfiableRange(..) => StatusCode::RANGE_NOT_SATISFIABLE,
+        Error::CacheInconsistency(..) | Error::Storage(..) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Build the headers to return to the client alongside the status for an [`Error`].
+///
+/// For [`Error::UnsatisfiableRange`], this is the `content-range` header containing the
+/// complete length, as required by RFC 7233. Otherwise, no headers are returned.
+fn error_headers(error: &Error) -> HeaderMap {
+    match error {
+        Error::UnsatisfiableRange(len) => render::unsatisfied_range_headers(*len),
+        _ => None,
+    }
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    use chrono::TimeZone;
+    use futures::StreamExt;
+    use http::HeaderValue;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{HTTPRequestBackend, OriginPool, Route};
+
+    /// An origin which answers every request with a cacheable `201 Created`, and records
+    /// the requests it receives, including their chunked bodies.
+    async fn recording_origin() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let recorded = recorded.clone();
+
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n0\r\n\r\n") {
+                        let mut buf = [0; 1024];
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+
+                    let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                    recorded.lock().unwrap().push(request);
+
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 201 Created\r\ncache-control: max-age=60\r\n\
+                              content-length: 7\r\n\r\ncreated",
+                        )
+                        .await;
+                });
+            }
+        });
+
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn test_passthrough() {
+        let (addr, requests) = recording_origin().await;
+        let origins = OriginPool::new(vec![format!("http://{addr}").parse().unwrap()]);
+        let mut injected = HeaderMap::new();
+        injected.insert("x-injected", HeaderValue::from_static("1"));
+        let backend =
+            Arc::new(HTTPRequestBackend::new(origins, 1 << 20).with_injected_headers(injected));
+        let route = Route::new(None, "/", backend.clone()).with_passthrough(backend);
+        let service = HTTPService::new(RouteTable::new().with_route(route), 1 << 20);
+
+        // Requests are sent upstream every time with their query and body, and are not cached.
+        for _ in 0..2 {
+            let body = stream::iter([Ok::<_, Infallible>(Bytes::from_static(b"data"))]);
+            let response = service
+                .call(&Method::POST, "/api", Some("x=1"), &HeaderMap::new(), body)
+                .await;
+            let (status, headers, body) = response.into_parts();
+            assert_eq!(status, StatusCode::CREATED);
+            assert_eq!(headers["cache-status"], "cache_streamer; fwd=method");
+
+            let body: Vec<_> = body.map(|chunk| chunk.unwrap()).collect().await;
+            assert_eq!(body.concat(), b"created");
+        }
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("post /api?x=1 "));
+        assert!(requests[0].contains("x-injected: 1"));
+        assert!(requests[0].contains("\r\n\r\n4\r\ndata\r\n"));
+    }
+
+    fn t(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    /// The `cache-status` and `age` headers of a response inserted at `t(0)` with the given
+    /// headers, and served at `t(now)`.
+    fn cache_headers(status: CacheStatus, headers: &[(&str, &str)], now: i64) -> (String, u64) {
+        let mut headers: HeaderMap = headers
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_str(value).unwrap()))
+            .collect();
+        let detail = CacheDetail {
+            status,
+            insertion_time: t(0),
+        };
+        put_cache_headers(&mut headers, &detail, t(now));
+
+        let cache_status = headers["cache-status"].to_str().unwrap().to_owned();
+        let age = headers.typed_get::<Age>().unwrap().as_secs();
+        (cache_status, age)
+    }
+
+    #[test]
+    fn test_cache_status() {
+        let hit = cache_headers(CacheStatus::Hit, &[], 0);
+        assert_eq!(hit, ("cache_streamer; hit".into(), 0));
+
+        let partial = cache_headers(CacheStatus::PartialHit, &[], 0);
+        assert_eq!(partial, ("cache_streamer; fwd=partial".into(), 0));
+
+        let miss = cache_headers(CacheStatus::Miss, &[], 0);
+        assert_eq!(miss, ("cache_streamer; fwd=uri-miss; stored".into(), 0));
+    }
+
+    #[test]
+    fn test_cache_status_appended() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cache-status", HeaderValue::from_static("upstream; hit"));
+        let detail = CacheDetail {
+            status: CacheStatus::Hit,
+            insertion_time: t(0),
+        };
+        put_cache_headers(&mut headers, &detail, t(0));
+
+        let entries: Vec<_> = headers.get_all("cache-status").iter().collect();
+        assert_eq!(entries, ["upstream; hit", "cache_streamer; hit"]);
+    }
+
+    #[test]
+    fn test_age() {
+        // The age accumulates from the stored age by the time spent in the cache.
+        assert_eq!(cache_headers(CacheStatus::Hit, &[], 30).1, 30);
+        assert_eq!(
+            cache_headers(CacheStatus::Hit, &[("age", "100")], 30).1,
+            130
+        );
+
+        // Invalid stored ages are ignored.
+        assert_eq!(
+            cache_headers(CacheStatus::Hit, &[("age", "soon")], 30).1,
+            30
+        );
+    }
+}
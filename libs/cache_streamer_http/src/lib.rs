@@ -1,13 +1,23 @@
+pub use cache_streamer_lib::storage;
+pub use cache_streamer_lib::types::StorageBackend;
+pub use chrono::TimeDelta;
+pub use client_options::ClientOptions;
 pub use http_request_backend::HTTPRequestBackend;
 pub use http_requester::HTTPRequester;
 pub use http_response::HTTPResponse;
 pub use http_service::HTTPService;
+pub use origin_pool::{OriginPool, Selection};
 pub use reqwest::Url;
+pub use route_table::{Route, RouteTable};
 
+mod client_options;
+mod digest;
 mod header_util;
 mod http_request_backend;
 mod http_requester;
 mod http_response;
 mod http_service;
+mod origin_pool;
 mod parse;
 mod render;
+mod route_table;
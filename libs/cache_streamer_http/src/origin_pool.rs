@@ -0,0 +1,309 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http::Method;
+use reqwest::{Client, RequestBuilder, Url};
+
+use crate::client_options::ClientOptions;
+
+/// How an origin is selected from an [`OriginPool`] for each request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Selection {
+    /// Take turns between the origins.
+    #[default]
+    RoundRobin,
+
+    /// Pick the origin with the fewest requests in flight, including response bodies
+    /// which are still being read.
+    LeastConnections,
+}
+
+/// A pool of upstream origins which serve identical content, such as mirrors of the
+/// same bucket.
+///
+/// Health is tracked passively: an origin which fails to connect `max_failures` times in
+/// a row is taken out of the pool for a cooldown, after which it is tried again. When
+/// every origin is out of the pool, all of them are tried anyway.
+pub struct OriginPool {
+    origins: Vec<Origin>,
+    selection: Selection,
+    max_failures: usize,
+    cooldown: Duration,
+    next: AtomicUsize,
+}
+
+/// An origin in an [`OriginPool`], and what is known about it.
+struct Origin {
+    base_url: Url,
+    client: Client,
+    request_url: Url,
+    active: AtomicUsize,
+    failures: AtomicUsize,
+    down_until: Mutex<Option<Instant>>,
+    ignores_ranges: AtomicBool,
+}
+
+impl OriginPool {
+    /// Create a new [`OriginPool`] of origins with the given base URLs, which fix the
+    /// scheme, host and port of requests.
+    ///
+    /// By default, origins are selected round-robin, and an origin is taken out of the
+    /// pool for 10 seconds after 3 consecutive connection failures. Each origin is
+    /// connected to through its own client, built with the default [`ClientOptions`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `base_urls` is empty.
+    pub fn new(base_urls: Vec<Url>) -> Self {
+        assert!(!base_urls.is_empty(), "origin pool must not be empty");
+
+        let options = ClientOptions::default();
+        let origins = base_urls
+            .into_iter()
+            .map(|base_url| {
+                let (client, request_url) = options.build(&base_url);
+
+                Origin {
+                    base_url,
+                    client,
+                    request_url,
+                    active: AtomicUsize::default(),
+                    failures: AtomicUsize::default(),
+                    down_until: Mutex::default(),
+                    ignores_ranges: AtomicBool::default(),
+                }
+            })
+            .collect();
+
+        Self {
+            origins,
+            selection: Selection::default(),
+            max_failures: 3,
+            cooldown: Duration::from_secs(10),
+            next: AtomicUsize::default(),
+        }
+    }
+
+    /// Set how an origin is selected for each request.
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Connect to the origins through clients built with `options`, such as to trust a
+    /// private certificate authority.
+    pub fn with_client_options(mut self, options: &ClientOptions) -> Self {
+        for origin in &mut self.origins {
+            (origin.client, origin.request_url) = options.build(&origin.base_url);
+        }
+
+        self
+    }
+
+    /// Take an origin out of the pool for `cooldown` after `max_failures` consecutive
+    /// connection failures.
+    pub fn with_health(mut self, max_failures: usize, cooldown: Duration) -> Self {
+        self.max_failures = max_failures.max(1);
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Select an origin for a request, skipping the origins at the indices in `tried`.
+    /// The origin counts as having a request in flight until the returned [`Connection`]
+    /// is dropped.
+    ///
+    /// Origins which are out of the pool are only selected if all untried origins are.
+    /// Returns [`None`] once every origin has been tried.
+    pub(crate) fn select(self: &Arc<Self>, tried: &[usize]) -> Option<Connection> {
+        let now = Instant::now();
+        let untried = Vec::from_iter((0..self.origins.len()).filter(|i| !tried.contains(i)));
+        let healthy = Vec::from_iter(untried.iter().copied().filter(|&i| self.is_up(i, now)));
+
+        let candidates = match healthy.is_empty() {
+            true => untried,
+            false => healthy,
+        };
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        // Rotate the candidates, so that ties between origins are also taken in turn.
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut rotated = (0..candidates.len()).map(|k| candidates[(start + k) % candidates.len()]);
+
+        let index = match self.selection {
+            Selection::RoundRobin => rotated.next()?,
+            Selection::LeastConnections => {
+                rotated.min_by_key(|&i| self.origins[i].active.load(Ordering::Relaxed))?
+            }
+        };
+
+        self.origins[index].active.fetch_add(1, Ordering::Relaxed);
+
+        Some(Connection {
+            pool: self.clone(),
+            index,
+        })
+    }
+
+    /// Returns whether the origin at `index` is in the pool at `now`.
+    fn is_up(&self, index: usize, now: Instant) -> bool {
+        let down_until = self.origins[index].down_until.lock().unwrap();
+        down_until.is_none_or(|until| until <= now)
+    }
+}
+
+/// A request in flight to an origin selected from an [`OriginPool`].
+pub(crate) struct Connection {
+    pool: Arc<OriginPool>,
+    index: usize,
+}
+
+impl Connection {
+    /// Get the index of the origin in the pool.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Get the base URL of the origin.
+    pub fn base_url(&self) -> &Url {
+        &self.origin().base_url
+    }
+
+    /// Build a request with `method` for `path` on the origin, through its client.
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.request_with_query(method, path, None)
+    }
+
+    /// Build a request with `method` for `path` and the query string `query` on the origin,
+    /// through its client.
+    pub fn request_with_query(
+        &self,
+        method: Method,
+        path: &str,
+        query: Option<&str>,
+    ) -> RequestBuilder {
+        let origin = self.origin();
+        let mut url = origin.request_url.clone();
+        url.set_path(path);
+        url.set_query(query);
+
+        origin.client.request(method, url)
+    }
+
+    /// Get whether the origin is known to ignore ranges, which is shared between all
+    /// requests to the origin so that it is only detected once.
+    pub fn ignores_ranges(&self) -> &AtomicBool {
+        &self.origin().ignores_ranges
+    }
+
+    /// Record that the origin responded, putting it back in the pool if it was out.
+    pub fn report_success(&self) {
+        let origin = self.origin();
+        origin.failures.store(0, Ordering::Relaxed);
+        *origin.down_until.lock().unwrap() = None;
+    }
+
+    /// Record that connecting to the origin failed, or that it responded with a server
+    /// error, taking it out of the pool if it has failed too many times in a row.
+    pub fn report_failure(&self) {
+        let origin = self.origin();
+        let failures = origin.failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if failures >= self.pool.max_failures {
+            origin.failures.store(0, Ordering::Relaxed);
+            *origin.down_until.lock().unwrap() = Some(Instant::now() + self.pool.cooldown);
+
+            tracing::warn!(
+                origin = %origin.base_url,
+                failures,
+                "origin taken out of the pool after repeated failures"
+            );
+        }
+    }
+
+    fn origin(&self) -> &Origin {
+        &self.pool.origins[self.index]
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.origin().active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(count: usize) -> OriginPool {
+        let urls = (0..count).map(|i| format!("http://origin{i}.test").parse().unwrap());
+        OriginPool::new(urls.collect())
+    }
+
+    fn select(pool: &Arc<OriginPool>, tried: &[usize]) -> Option<usize> {
+        pool.select(tried).map(|connection| connection.index())
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = Arc::new(pool(3));
+
+        let selected = Vec::from_iter((0..6).map(|_| select(&pool, &[]).unwrap()));
+        assert_eq!(selected, [0, 1, 2, 0, 1, 2]);
+
+        assert_eq!(select(&pool, &[0, 2]), Some(1));
+        assert_eq!(select(&pool, &[0, 1, 2]), None);
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let pool = Arc::new(pool(2).with_selection(Selection::LeastConnections));
+
+        let first = pool.select(&[]).unwrap();
+        assert_eq!(first.index(), 0);
+
+        // The first origin has a request in flight until its connection is dropped.
+        assert_eq!(select(&pool, &[]), Some(1));
+        assert_eq!(select(&pool, &[]), Some(1));
+
+        // Ties are taken in turn.
+        drop(first);
+        let selected = Vec::from_iter((0..4).map(|_| select(&pool, &[]).unwrap()));
+        assert_eq!(selected, [1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_health() {
+        let pool = Arc::new(pool(2).with_health(2, Duration::from_secs(60)));
+
+        let connection = pool.select(&[0]).unwrap();
+        assert_eq!(connection.index(), 1);
+        connection.report_failure();
+        connection.report_failure();
+        drop(connection);
+
+        // The second origin is out of the pool, unless it is the only one left.
+        let selected = Vec::from_iter((0..3).map(|_| select(&pool, &[]).unwrap()));
+        assert_eq!(selected, [0, 0, 0]);
+        assert_eq!(select(&pool, &[0]), Some(1));
+
+        // Responding puts it back in the pool.
+        pool.select(&[0]).unwrap().report_success();
+        let selected = Vec::from_iter((0..2).map(|_| select(&pool, &[]).unwrap()));
+        assert!(selected.contains(&1));
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let pool = Arc::new(pool(2).with_health(1, Duration::ZERO));
+
+        pool.select(&[0]).unwrap().report_failure();
+
+        let selected = Vec::from_iter((0..2).map(|_| select(&pool, &[]).unwrap()));
+        assert!(selected.contains(&1));
+    }
+}
@@ -1,6 +1,12 @@
+use std::time::{Duration, SystemTime};
+
 use cache_streamer_lib::types::{RequestRange, ResponseRange};
-use chrono::{DateTime, Utc};
-use headers::{CacheControl, ContentLength, ContentRange, HeaderMap, HeaderMapExt};
+use chrono::{DateTime, TimeDelta, Utc};
+use headers::{
+    Age, CacheControl, ContentLength, ContentRange, Date, ETag, Expires, HeaderMap, HeaderMapExt,
+    IfNoneMatch, LastModified,
+};
+use http::header::{self, HeaderName};
 use range_header::{ByteRangeSpec, Range};
 
 /// Converts HTTP request `range` to [`RequestRange`].
@@ -90,30 +96,202 @@ pub fn into_response_range(
     })
 }
 
-/// Determines whether the given headers correspond to a cacheable response, and if so,
-/// if and when that response would expire.
+/// Determines whether the given headers correspond to a response which can be stored by a
+/// shared cache, and if so, if and when that response would expire, following RFC 9111.
+///
+/// `request_time` and `response_time` are the times at which the upstream request was sent
+/// and its response was received, used to correct the age of the response.
+///
+/// Responses are not cacheable if `cache-control` contains `no-store`, `private`, or
+/// `no-cache`, since this cache never revalidates stored responses.
+///
+/// The freshness lifetime is taken from the first of the following which is present:
+/// * `s-maxage`
+/// * `max-age`
+/// * `expires`, relative to `date`; an invalid `expires` means the response is already stale
+/// * 10% of the time since `last-modified`, as a heuristic
 ///
-/// Responses are cacheable if the `cache-control` header is not present, or present
-/// and does not contain `no-cache` / `no-store`.
+/// The expiration time is the time at which the current age of the response reaches its
+/// freshness lifetime. Without a freshness lifetime, the expiration time is [`None`] and the
+/// response never expires, unless `must-revalidate` or `proxy-revalidate` is present, in which
+/// case it would already be stale, so it is not cacheable.
 ///
-/// The expiration time is calculated from `max-age` if it is present, or [`None`] if it is not.
+/// `immutable` needs no special handling, since fresh responses are never revalidated.
 pub fn get_cache_possible_and_expire_time(
     response_headers: &HeaderMap,
+    request_time: DateTime<Utc>,
+    response_time: DateTime<Utc>,
 ) -> (bool, Option<DateTime<Utc>>) {
-    let cache_control = match response_headers.typed_get::<CacheControl>() {
-        Some(header) => header,
-        None => {
-            // No cache-control header, so no restrictions.
-            return (true, None);
-        }
-    };
+    let cache_control = response_headers
+        .typed_get::<CacheControl>()
+        .unwrap_or_else(CacheControl::new);
 
-    if cache_control.no_cache() || cache_control.no_store() {
+    if cache_control.no_store() || cache_control.private() || cache_control.no_cache() {
         // Not allowed to cache.
         return (false, None);
     }
 
-    (true, cache_control.max_age().map(|age| Utc::now() + age))
+    let date = response_date(response_headers, response_time);
+
+    let lifetime = match freshness_lifetime(response_headers, &cache_control, date) {
+        Some(lifetime) => lifetime,
+        None if cache_control.must_revalidate()
+            || has_directive(response_headers, "proxy-revalidate") =>
+        {
+            return (false, None)
+        }
+        None => return (true, None),
+    };
+
+    let age = get_initial_age(response_headers, request_time, response_time);
+
+    // Responses which are already stale expire when received, and lifetimes too long to
+    // represent never expire.
+    let remaining = (lifetime - age).max(TimeDelta::zero());
+
+    (true, response_time.checked_add_signed(remaining))
+}
+
+/// Determines whether a shared cache may store a response to a request containing
+/// `authorization`, which requires `public`, `s-maxage`, or `must-revalidate` in the
+/// response `cache-control` header as per RFC 9111 section 3.5.
+pub fn is_shareable_with_authorization(response_headers: &HeaderMap) -> bool {
+    response_headers
+        .typed_get::<CacheControl>()
+        .is_some_and(|cache_control| {
+            cache_control.public()
+                || cache_control.s_max_age().is_some()
+                || cache_control.must_revalidate()
+        })
+}
+
+/// Compute the freshness lifetime of a response, or [`None`] if neither an explicit nor a
+/// heuristic lifetime is available.
+fn freshness_lifetime(
+    response_headers: &HeaderMap,
+    cache_control: &CacheControl,
+    date: DateTime<Utc>,
+) -> Option<TimeDelta> {
+    if let Some(age) = cache_control.s_max_age().or(cache_control.max_age()) {
+        return Some(delta(age));
+    }
+
+    if response_headers.contains_key(header::EXPIRES) {
+        // Invalid dates such as "0" represent a time in the past.
+        let lifetime = response_headers
+            .typed_get::<Expires>()
+            .map(|expires| DateTime::<Utc>::from(SystemTime::from(expires)) - date)
+            .unwrap_or_default();
+
+        return Some(lifetime.max(TimeDelta::zero()));
+    }
+
+    let last_modified = response_headers.typed_get::<LastModified>()?;
+    let last_modified = DateTime::<Utc>::from(SystemTime::from(last_modified));
+
+    Some((date - last_modified).max(TimeDelta::zero()) / 10)
+}
+
+/// Compute the age of a response when it was received, correcting for clock skew
+/// and network delay as per RFC 9111 section 4.2.3.
+///
+/// `request_time` and `response_time` are the times at which the upstream request was sent
+/// and its response was received.
+pub fn get_initial_age(
+    response_headers: &HeaderMap,
+    request_time: DateTime<Utc>,
+    response_time: DateTime<Utc>,
+) -> TimeDelta {
+    let age_value = response_headers
+        .typed_get::<Age>()
+        .map(|age| delta(Duration::from_secs(age.as_secs())))
+        .unwrap_or_default();
+
+    let date = response_date(response_headers, response_time);
+    let apparent_age = (response_time - date).max(TimeDelta::zero());
+    let response_delay = (response_time - request_time).max(TimeDelta::zero());
+
+    let corrected_age_value = age_value
+        .checked_add(&response_delay)
+        .unwrap_or(TimeDelta::MAX);
+
+    apparent_age.max(corrected_age_value)
+}
+
+/// Get the names of the request headers listed by the `vary` response header, in order.
+///
+/// Returns [`None`] if the response varies on `*`, meaning it cannot be reused for other
+/// requests.
+pub fn get_vary_names(response_headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+
+    let values = response_headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty());
+
+    for name in values {
+        if name == "*" {
+            return None;
+        }
+
+        if let Ok(name) = name.parse::<HeaderName>() {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    Some(names)
+}
+
+/// Determines whether a request with an `if-none-match` header should be answered with
+/// 304 Not Modified instead of the response with the given headers, as per RFC 9110
+/// section 13.1.2.
+///
+/// The condition matches if any entity tag matches the `etag` of the response using the
+/// weak comparison, or if it is `*`.
+pub fn is_not_modified(request_headers: &HeaderMap, response_headers: &HeaderMap) -> bool {
+    let Some(if_none_match) = request_headers.typed_get::<IfNoneMatch>() else {
+        return false;
+    };
+
+    match response_headers.typed_get::<ETag>() {
+        Some(etag) => !if_none_match.precondition_passes(&etag),
+        None => if_none_match == IfNoneMatch::any(),
+    }
+}
+
+/// Get the time at which a response was generated from its `date` header.
+///
+/// Responses without a valid date are treated as generated when they were received.
+fn response_date(response_headers: &HeaderMap, response_time: DateTime<Utc>) -> DateTime<Utc> {
+    response_headers
+        .typed_get::<Date>()
+        .map(|date| DateTime::<Utc>::from(SystemTime::from(date)))
+        .unwrap_or(response_time)
+}
+
+/// Determines whether the `cache-control` header contains the given directive.
+///
+/// This is used for directives which are not exposed by [`CacheControl`], such as
+/// `proxy-revalidate`.
+fn has_directive(response_headers: &HeaderMap, directive: &str) -> bool {
+    response_headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|value| value.split('=').next())
+        .any(|name| name.trim().eq_ignore_ascii_case(directive))
+}
+
+/// Convert a [`Duration`] to a [`TimeDelta`], saturating if it is out of range.
+fn delta(duration: Duration) -> TimeDelta {
+    TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX)
 }
 
 /// Fallibly convert a `u64` length to a `usize` length with a very short method name.
@@ -122,3 +300,187 @@ pub fn get_cache_possible_and_expire_time(
 fn l(x: u64) -> Option<usize> {
     x.try_into().ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn t(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn http_date(time: DateTime<Utc>) -> String {
+        time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    }
+
+    fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+
+        headers
+    }
+
+    /// Freshness for a response received at `t(0)` from a request sent at `t(0)`.
+    fn freshness(pairs: &[(&'static str, String)]) -> (bool, Option<DateTime<Utc>>) {
+        get_cache_possible_and_expire_time(&headers(pairs), t(0), t(0))
+    }
+
+    fn cc(value: &str) -> (&'static str, String) {
+        ("cache-control", value.into())
+    }
+
+    #[test]
+    fn test_no_headers() {
+        assert_eq!(freshness(&[]), (true, None));
+    }
+
+    #[test]
+    fn test_not_storable() {
+        assert_eq!(freshness(&[cc("no-store")]), (false, None));
+        assert_eq!(freshness(&[cc("private, max-age=60")]), (false, None));
+        assert_eq!(freshness(&[cc("no-cache")]), (false, None));
+    }
+
+    #[test]
+    fn test_max_age() {
+        assert_eq!(freshness(&[cc("max-age=60")]), (true, Some(t(60))));
+    }
+
+    #[test]
+    fn test_s_maxage() {
+        assert_eq!(
+            freshness(&[cc("max-age=60, s-maxage=120")]),
+            (true, Some(t(120)))
+        );
+    }
+
+    #[test]
+    fn test_expires() {
+        let pairs = [("date", http_date(t(-10))), ("expires", http_date(t(50)))];
+        assert_eq!(freshness(&pairs), (true, Some(t(50))));
+
+        // max-age takes priority over expires.
+        let pairs = [cc("max-age=10"), ("expires", http_date(t(50)))];
+        assert_eq!(freshness(&pairs), (true, Some(t(10))));
+    }
+
+    #[test]
+    fn test_invalid_expires() {
+        assert_eq!(freshness(&[("expires", "0".into())]), (true, Some(t(0))));
+    }
+
+    #[test]
+    fn test_date_correction() {
+        // The response was generated 10s before it was received.
+        let pairs = [cc("max-age=60"), ("date", http_date(t(-10)))];
+        assert_eq!(freshness(&pairs), (true, Some(t(50))));
+
+        // Dates in the future are ignored.
+        let pairs = [cc("max-age=60"), ("date", http_date(t(10)))];
+        assert_eq!(freshness(&pairs), (true, Some(t(60))));
+    }
+
+    #[test]
+    fn test_age_correction() {
+        // The age is increased by the time taken for the response to arrive.
+        let pairs = headers(&[cc("max-age=60"), ("age", "5".into())]);
+        assert_eq!(
+            get_cache_possible_and_expire_time(&pairs, t(-2), t(0)),
+            (true, Some(t(53)))
+        );
+
+        // The larger of the apparent and corrected ages is used.
+        let pairs = headers(&[
+            cc("max-age=60"),
+            ("age", "5".into()),
+            ("date", http_date(t(-20))),
+        ]);
+        assert_eq!(
+            get_cache_possible_and_expire_time(&pairs, t(-2), t(0)),
+            (true, Some(t(40)))
+        );
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert_eq!(freshness(&[cc("max-age=99999999999999")]), (true, None));
+
+        let pairs = [cc("max-age=60"), ("age", "99999999999999".into())];
+        assert_eq!(freshness(&pairs), (true, Some(t(0))));
+    }
+
+    #[test]
+    fn test_heuristic_freshness() {
+        let pairs = [
+            ("date", http_date(t(0))),
+            ("last-modified", http_date(t(-1000))),
+        ];
+        assert_eq!(freshness(&pairs), (true, Some(t(100))));
+    }
+
+    #[test]
+    fn test_must_revalidate() {
+        // Without a lifetime, they would be stale when stored.
+        assert_eq!(freshness(&[cc("must-revalidate")]), (false, None));
+        assert_eq!(freshness(&[cc("proxy-revalidate")]), (false, None));
+        assert_eq!(
+            freshness(&[cc("must-revalidate, max-age=60")]),
+            (true, Some(t(60)))
+        );
+    }
+
+    #[test]
+    fn test_immutable() {
+        assert_eq!(
+            freshness(&[cc("immutable, max-age=60")]),
+            (true, Some(t(60)))
+        );
+    }
+
+    #[test]
+    fn test_vary_names() {
+        let vary = |value: &str| get_vary_names(&headers(&[("vary", value.into())]));
+
+        assert_eq!(get_vary_names(&HeaderMap::new()), Some(vec![]));
+        assert_eq!(
+            vary("Accept, accept-encoding,Accept"),
+            Some(vec![header::ACCEPT, header::ACCEPT_ENCODING])
+        );
+        assert_eq!(vary("accept, *"), None);
+    }
+
+    #[test]
+    fn test_shareable_with_authorization() {
+        let shareable = |value| is_shareable_with_authorization(&headers(&[cc(value)]));
+
+        assert!(shareable("public"));
+        assert!(shareable("s-maxage=60"));
+        assert!(shareable("must-revalidate"));
+        assert!(!shareable("max-age=60"));
+        assert!(!is_shareable_with_authorization(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let response = headers(&[("etag", "\"abc\"".into())]);
+
+        let request = headers(&[("if-none-match", "\"xyz\", \"abc\"".into())]);
+        assert!(is_not_modified(&request, &response));
+        let request = headers(&[("if-none-match", "W/\"abc\"".into())]);
+        assert!(is_not_modified(&request, &response));
+        let request = headers(&[("if-none-match", "*".into())]);
+        assert!(is_not_modified(&request, &response));
+        assert!(is_not_modified(&request, &HeaderMap::new()));
+
+        let request = headers(&[("if-none-match", "\"xyz\"".into())]);
+        assert!(!is_not_modified(&request, &response));
+        assert!(!is_not_modified(&request, &HeaderMap::new()));
+        assert!(!is_not_modified(&HeaderMap::new(), &response));
+    }
+}
@@ -1,5 +1,7 @@
 use cache_streamer_lib::types::{RequestRange, ResponseRange};
-use headers::{ContentLength, ContentRange, HeaderMap, HeaderMapExt};
+use chrono::TimeDelta;
+use headers::{Age, ContentLength, ContentRange, HeaderMap, HeaderMapExt};
+use http::{header, HeaderName, HeaderValue};
 use range_header::ByteRangeBuilder;
 
 /// Returns a [`HeaderMap`] containing the required headers to fetch the given [`RequestRange`].
@@ -50,6 +52,57 @@ pub fn put_response_range(headers: HeaderMap, range: ResponseRange) -> Option<He
     }
 }
 
+/// Returns a [`HeaderMap`] containing the HTTP `content-range` header for a range which
+/// is not satisfiable, such as `bytes */1234`.
+///
+/// [`None`] will be returned only if the length cannot be converted.
+pub fn unsatisfied_range_headers(complete_length: usize) -> Option<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    headers.typed_insert(ContentRange::unsatisfied_bytes(l(complete_length)?));
+
+    Some(headers)
+}
+
+/// Sets the HTTP `age` header in the given [`HeaderMap`] to the given age, rounded down
+/// to whole seconds. Negative ages are treated as zero.
+pub fn put_age(headers: &mut HeaderMap, age: TimeDelta) {
+    let secs = age.num_seconds().try_into().unwrap_or(0);
+    headers.typed_insert(Age::from_secs(secs));
+}
+
+/// Appends an RFC 9211 `cache-status` entry for this cache, with the given parameters,
+/// to the given [`HeaderMap`].
+///
+/// Existing entries from caches closer to the upstream server are kept before it.
+pub fn put_cache_status(headers: &mut HeaderMap, params: &str) {
+    const CACHE_STATUS: HeaderName = HeaderName::from_static("cache-status");
+
+    if let Ok(value) = HeaderValue::from_str(&format!("cache_streamer; {params}")) {
+        headers.append(CACHE_STATUS, value);
+    }
+}
+
+/// Returns the headers of a 304 Not Modified response in place of a response with the given
+/// headers.
+///
+/// As per RFC 9110 section 15.4.5, this keeps the headers which would have been sent with
+/// the full response, except for those describing its content.
+pub fn not_modified_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+
+    for name in [
+        header::CONTENT_DISPOSITION,
+        header::CONTENT_ENCODING,
+        header::CONTENT_LENGTH,
+        header::CONTENT_RANGE,
+        header::CONTENT_TYPE,
+    ] {
+        headers.remove(name);
+    }
+
+    headers
+}
+
 /// Adds the HTTP `content-length` header to the given [`HeaderMap`].
 ///
 /// Returns [`None`] if the conversion fails.
@@ -0,0 +1,455 @@
+use std::cmp::Ordering;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+
+use cache_streamer_lib::types::{RequestBackend, RequestRange, Requester, RequesterStatus, Result};
+use http::header;
+use http::HeaderMap;
+
+use crate::http_request_backend::HTTPRequestBackend;
+use crate::http_response::HTTPResponse;
+use crate::{header_util, parse};
+
+/// A route from requests for a hostname and path prefix to the backend which fetches
+/// them from upstream.
+pub struct Route {
+    name: String,
+    host: Option<String>,
+    prefix: String,
+    backend: Arc<dyn RequestBackend<String, HTTPResponse>>,
+    passthrough: Option<Arc<HTTPRequestBackend>>,
+}
+
+impl Route {
+    /// Create a new [`Route`] for requests to `host` whose path starts with the path
+    /// segments of `prefix`, such as `/videos`, which are fetched through `backend`.
+    ///
+    /// If `host` is [`None`], the route matches requests to any hostname. The full request
+    /// path is sent upstream, including the prefix.
+    pub fn new(
+        host: Option<&str>,
+        prefix: &str,
+        backend: Arc<dyn RequestBackend<String, HTTPResponse>>,
+    ) -> Self {
+        let host = host.map(str::to_ascii_lowercase);
+        let prefix = format!("/{}", prefix.trim_matches('/'));
+        let name = format!("{}{prefix}", host.as_deref().unwrap_or("*"));
+
+        Self {
+            name,
+            host,
+            prefix,
+            backend,
+            passthrough: None,
+        }
+    }
+
+    /// Proxy requests with methods other than HTTP `GET` and `HEAD` to the upstream
+    /// server through `backend`, without caching them.
+    ///
+    /// Without this, such requests are answered with `405 Method Not Allowed`.
+    pub fn with_passthrough(mut self, backend: Arc<HTTPRequestBackend>) -> Self {
+        self.passthrough = Some(backend);
+        self
+    }
+
+    /// Get the name of the route, such as `example.com/videos`, or `*/videos` if it
+    /// matches any hostname. Responses are cached separately for each route name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the backend for requests with methods other than HTTP `GET` and `HEAD`.
+    pub(crate) fn passthrough(&self) -> Option<&Arc<HTTPRequestBackend>> {
+        self.passthrough.as_ref()
+    }
+
+    /// Returns whether the route matches a request for `path` to `host`.
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match &self.host {
+            Some(route_host) => host.is_some_and(|host| host == route_host),
+            None => true,
+        };
+
+        let path_matches = match path.strip_prefix(self.prefix.trim_end_matches('/')) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        };
+
+        host_matches && path_matches
+    }
+}
+
+/// The routes of a service, from the hostname and path of requests to the backends which
+/// fetch them.
+#[derive(Default)]
+pub struct RouteTable {
+    routes: Vec<Arc<Route>>,
+}
+
+impl RouteTable {
+    /// Create a new, empty [`RouteTable`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a route to the table.
+    ///
+    /// Routes for a specific hostname take priority over routes for any hostname, then
+    /// routes with longer prefixes take priority over routes with shorter prefixes.
+    pub fn with_route(mut self, route: Route) -> Self {
+        self.routes.push(Arc::new(route));
+        self
+    }
+
+    /// Find the route for a request for `path` with the given request headers, which is
+    /// matched against the hostname in the `host` header.
+    pub(crate) fn find(&self, path: &str, headers: &HeaderMap) -> Option<&Arc<Route>> {
+        let host = request_host(headers);
+
+        self.routes
+            .iter()
+            .filter(|route| route.matches(host.as_deref(), path))
+            .max_by_key(|route| (route.host.is_some(), route.prefix.len()))
+    }
+
+    /// Get the route with the given name.
+    fn get(&self, name: &str) -> Option<&Arc<Route>> {
+        self.routes.iter().find(|route| route.name() == name)
+    }
+}
+
+/// The routes of a service, which are replaced when its configuration is reloaded.
+pub(crate) type SharedRoutes = Arc<RwLock<Arc<RouteTable>>>;
+
+/// The cache key of a response, which is the route it was fetched through and its path.
+///
+/// Keys are compared by the name of the route, so that responses for identical paths on
+/// different routes never collide.
+#[derive(Clone)]
+pub(crate) struct RouteKey {
+    pub route: Arc<Route>,
+    pub path: String,
+}
+
+impl RouteKey {
+    fn parts(&self) -> (&str, &str) {
+        (self.route.name(), &self.path)
+    }
+}
+
+impl PartialEq for RouteKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.parts() == other.parts()
+    }
+}
+
+impl Eq for RouteKey {}
+
+impl PartialOrd for RouteKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RouteKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.parts().cmp(&other.parts())
+    }
+}
+
+/// [`RequestBackend`] which creates requesters through the backend of the route in the key.
+///
+/// When the routes are replaced, the requesters of cached responses fetch through the new
+/// route with the same name, so that refills reach its origins with its policies.
+pub(crate) struct RouteBackend {
+    routes: SharedRoutes,
+}
+
+impl RouteBackend {
+    pub fn new(routes: SharedRoutes) -> Self {
+        Self { routes }
+    }
+}
+
+impl RequestBackend<RouteKey, HTTPResponse> for RouteBackend {
+    fn create_for_key(
+        &self,
+        key: &RouteKey,
+        request: &HeaderMap,
+    ) -> Arc<dyn Requester<HTTPResponse>> {
+        let requester = key.route.backend.create_for_key(&key.path, request);
+
+        Arc::new(RouteRequester {
+            routes: self.routes.clone(),
+            path: key.path.clone(),
+            request: Arc::new(Mutex::new(request.clone())),
+            current: Mutex::new((key.route.clone(), requester)),
+        })
+    }
+}
+
+/// [`Requester`] which fetches through the current route with the name of the route it
+/// was created for. If the route has been removed, it keeps fetching through the old one.
+///
+/// Requesters for replaced routes are created from the request headers which the response
+/// varies on, once it is known, since they fetch on behalf of any client it is served to.
+struct RouteRequester {
+    routes: SharedRoutes,
+    path: String,
+    request: Arc<Mutex<HeaderMap>>,
+    current: Mutex<(Arc<Route>, Arc<dyn Requester<HTTPResponse>>)>,
+}
+
+impl RouteRequester {
+    /// Get the requester for the current route, creating it if the route was replaced.
+    fn requester(&self) -> Arc<dyn Requester<HTTPResponse>> {
+        let routes = self.routes.read().unwrap().clone();
+        let mut current = self.current.lock().unwrap();
+
+        if let Some(route) = routes.get(current.0.name()) {
+            if !Arc::ptr_eq(route, &current.0) {
+                let request = self.request.lock().unwrap().clone();
+                let requester = route.backend.create_for_key(&self.path, &request);
+                *current = (route.clone(), requester);
+            }
+        }
+
+        current.1.clone()
+    }
+}
+
+impl Requester<HTTPResponse> for RouteRequester {
+    fn fetch(
+        &self,
+        range: &RequestRange,
+    ) -> Pin<Box<dyn Future<Output = Result<RequesterStatus<HTTPResponse>>> + Send + Sync>> {
+        let status = self.requester().fetch(range);
+        let request = self.request.clone();
+
+        Box::pin(async move {
+            status
+                .await
+                .inspect(|status| keep_varying(&request, status))
+        })
+    }
+
+    fn fetch_metadata(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<RequesterStatus<HTTPResponse>>> + Send + Sync>> {
+        let status = self.requester().fetch_metadata();
+        let request = self.request.clone();
+
+        Box::pin(async move {
+            status
+                .await
+                .inspect(|status| keep_varying(&request, status))
+        })
+    }
+}
+
+/// Keep only the request headers which a cacheable response varies on.
+fn keep_varying(request: &Mutex<HeaderMap>, status: &RequesterStatus<HTTPResponse>) {
+    if let RequesterStatus::Cache(_, _, _, (_, headers))
+    | RequesterStatus::CacheUnknownLength(_, _, _, (_, headers)) = status
+    {
+        let names = parse::get_vary_names(headers).unwrap_or_default();
+        let mut request = request.lock().unwrap();
+        *request = header_util::forwarded_headers(&request, &names);
+    }
+}
+
+/// Get the lowercase hostname of a request from its `host` header, without the port.
+fn request_host(headers: &HeaderMap) -> Option<String> {
+    let host = headers.get(header::HOST)?.to_str().ok()?;
+    let authority = host.parse::<http::uri::Authority>().ok()?;
+
+    Some(authority.host().to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cache_streamer_lib::types::{Error, ResponseRange};
+    use http::HeaderValue;
+
+    use super::*;
+    use crate::{HTTPRequestBackend, OriginPool};
+
+    fn route(host: Option<&str>, prefix: &str) -> Route {
+        let origins = OriginPool::new(vec!["http://origin.test".parse().unwrap()]);
+        Route::new(host, prefix, Arc::new(HTTPRequestBackend::new(origins, 0)))
+    }
+
+    fn find<'a>(table: &'a RouteTable, host: &str, path: &str) -> Option<&'a str> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_str(host).unwrap());
+
+        table.find(path, &headers).map(|route| route.name())
+    }
+
+    #[test]
+    fn test_route_name() {
+        assert_eq!(route(None, "/").name(), "*/");
+        assert_eq!(
+            route(Some("Example.com"), "videos/").name(),
+            "example.com/videos"
+        );
+    }
+
+    #[test]
+    fn test_find() {
+        let table = RouteTable::new()
+            .with_route(route(None, "/"))
+            .with_route(route(None, "/videos"))
+            .with_route(route(Some("example.com"), "/"))
+            .with_route(route(Some("example.com"), "/videos/hd"));
+
+        assert_eq!(find(&table, "other.com", "/a.png"), Some("*/"));
+        assert_eq!(find(&table, "other.com", "/videos"), Some("*/videos"));
+        assert_eq!(find(&table, "other.com", "/videos/a.mp4"), Some("*/videos"));
+        assert_eq!(find(&table, "other.com", "/videosx"), Some("*/"));
+
+        // Routes for the hostname take priority, ignoring the case and port.
+        assert_eq!(
+            find(&table, "example.com", "/videos/a.mp4"),
+            Some("example.com/")
+        );
+        assert_eq!(
+            find(&table, "EXAMPLE.com:8080", "/a.png"),
+            Some("example.com/")
+        );
+        assert_eq!(
+            find(&table, "example.com", "/videos/hd/a.mp4"),
+            Some("example.com/videos/hd")
+        );
+    }
+
+    #[test]
+    fn test_find_none() {
+        let table = RouteTable::new().with_route(route(Some("example.com"), "/videos"));
+
+        assert_eq!(find(&table, "example.com", "/a.png"), None);
+        assert_eq!(find(&table, "other.com", "/videos/a.mp4"), None);
+        assert!(table.find("/videos/a.mp4", &HeaderMap::new()).is_none());
+    }
+
+    /// [`RequestBackend`] whose requesters fail with its label.
+    struct Labelled(&'static str);
+
+    impl RequestBackend<String, HTTPResponse> for Labelled {
+        fn create_for_key(&self, _: &String, _: &HeaderMap) -> Arc<dyn Requester<HTTPResponse>> {
+            Arc::new(Labelled(self.0))
+        }
+    }
+
+    impl Requester<HTTPResponse> for Labelled {
+        fn fetch(
+            &self,
+            _: &RequestRange,
+        ) -> Pin<Box<dyn Future<Output = Result<RequesterStatus<HTTPResponse>>> + Send + Sync>>
+        {
+            let label = self.0;
+            Box::pin(async move { Err(Error::UpstreamConnect(label.into())) })
+        }
+    }
+
+    fn labelled(prefix: &str, label: &'static str) -> RouteTable {
+        RouteTable::new().with_route(Route::new(None, prefix, Arc::new(Labelled(label))))
+    }
+
+    async fn fetched_through(requester: &Arc<dyn Requester<HTTPResponse>>) -> String {
+        match requester.fetch(&RequestRange::None).await {
+            Err(Error::UpstreamConnect(label)) => label.to_string(),
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replaced_route() {
+        let routes: SharedRoutes = Arc::new(RwLock::new(Arc::new(labelled("/", "old"))));
+        let key = RouteKey {
+            route: routes
+                .read()
+                .unwrap()
+                .find("/a", &HeaderMap::new())
+                .unwrap()
+                .clone(),
+            path: "/a".into(),
+        };
+        let requester = RouteBackend::new(routes.clone()).create_for_key(&key, &HeaderMap::new());
+        assert_eq!(fetched_through(&requester).await, "old");
+
+        // Requesters follow the new route with the same name.
+        *routes.write().unwrap() = Arc::new(labelled("/", "new"));
+        assert_eq!(fetched_through(&requester).await, "new");
+
+        // Without a route of the same name, they keep the last one.
+        *routes.write().unwrap() = Arc::new(labelled("/videos", "other"));
+        assert_eq!(fetched_through(&requester).await, "new");
+    }
+
+    /// [`RequestBackend`] which records the request headers it creates requesters with,
+    /// whose responses vary on `x-tenant`.
+    #[derive(Clone, Default)]
+    struct Recording(Arc<Mutex<Vec<HeaderMap>>>);
+
+    impl RequestBackend<String, HTTPResponse> for Recording {
+        fn create_for_key(
+            &self,
+            _: &String,
+            request: &HeaderMap,
+        ) -> Arc<dyn Requester<HTTPResponse>> {
+            self.0.lock().unwrap().push(request.clone());
+            Arc::new(self.clone())
+        }
+    }
+
+    impl Requester<HTTPResponse> for Recording {
+        fn fetch(
+            &self,
+            _: &RequestRange,
+        ) -> Pin<Box<dyn Future<Output = Result<RequesterStatus<HTTPResponse>>> + Send + Sync>>
+        {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::VARY, HeaderValue::from_static("x-tenant"));
+            let data = (http::StatusCode::OK, headers.clone());
+            let response = HTTPResponse::new(data.0, headers, Box::pin(futures::stream::empty()));
+            let range = ResponseRange {
+                bytes_len: 0,
+                bytes_range: RequestRange::None,
+            };
+
+            Box::pin(async move { Ok(RequesterStatus::Cache(response, range, None, data)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replaced_route_headers() {
+        let backend = Recording::default();
+        let table =
+            || RouteTable::new().with_route(Route::new(None, "/", Arc::new(backend.clone())));
+        let routes: SharedRoutes = Arc::new(RwLock::new(Arc::new(table())));
+        let key = RouteKey {
+            route: routes.read().unwrap().get("*/").unwrap().clone(),
+            path: "/a".into(),
+        };
+
+        let mut request = HeaderMap::new();
+        request.insert("x-tenant", HeaderValue::from_static("a"));
+        request.insert("x-forwarded-for", HeaderValue::from_static("192.0.2.1"));
+        let requester = RouteBackend::new(routes.clone()).create_for_key(&key, &request);
+        requester.fetch(&RequestRange::None).await.unwrap();
+
+        // Requesters for the new route are only given the headers the response varies on.
+        *routes.write().unwrap() = Arc::new(table());
+        requester.fetch(&RequestRange::None).await.unwrap();
+
+        let requests = backend.0.lock().unwrap();
+        assert_eq!(requests[0], request);
+        assert_eq!(requests[1].len(), 1);
+        assert_eq!(requests[1]["x-tenant"], "a");
+    }
+}
@@ -1,20 +1,176 @@
 use bytes::Bytes;
-use parking_lot::RwLock;
-use sparse_map::SparseMap;
-use std::sync::Arc;
+use core::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::Handle;
 
-/// The type of a file sparse map.
-#[derive(Default, Clone)]
-pub struct Blocks(Arc<RwLock<SparseMap<Bytes>>>);
+use crate::storage::MemoryStorage;
+use crate::types::Storage;
+
+/// The type of a file sparse map, backed by a [`Storage`].
+///
+/// Clones share the storage, whether the blocks are valid, and the check made once
+/// the blocks are complete.
+#[derive(Clone)]
+pub struct Blocks {
+    storage: Arc<dyn Storage>,
+    state: Arc<State>,
+}
+
+/// The state shared between clones of [`Blocks`].
+#[derive(Default)]
+struct State {
+    invalid: AtomicBool,
+    completion: OnceLock<Completion>,
+}
+
+/// A check of the contents of the blocks, made once all `len` bytes are stored.
+struct Completion {
+    len: usize,
+    checked: AtomicBool,
+    check: Box<CheckFn>,
+}
+
+/// The type of a check of the contents of complete blocks.
+type CheckFn = dyn Fn(&[Bytes]) -> bool + Send + Sync;
 
 impl Blocks {
-    /// See [`SparseMap::get`].
+    /// Create a new blocks object backed by the given storage.
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            state: Arc::default(),
+        }
+    }
+
+    /// See [`Storage::get`].
     pub fn get(&self, offset: usize, max_size: usize) -> Option<Bytes> {
-        self.0.read().get(offset, max_size)
+        self.storage.get(offset, max_size)
     }
 
-    /// See [`SparseMap::put_new`].
+    /// See [`Storage::put`].
+    ///
+    /// If this completes the blocks, they are checked; see [`Blocks::on_complete`].
     pub fn put_new(&self, offset: usize, data: Bytes) {
-        self.0.write().put_new(offset, data)
+        self.storage.put(offset, data);
+        self.check_complete();
+    }
+
+    /// See [`Storage::holes`].
+    pub fn holes(&self, range: Range<usize>) -> Vec<Range<usize>> {
+        self.storage.holes(range)
+    }
+
+    /// Mark the blocks as invalid, such as when the upstream body did not match its
+    /// declared length, so that the item they belong to is no longer served.
+    pub fn invalidate(&self) {
+        self.state.invalid.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the blocks have not been invalidated.
+    pub fn is_valid(&self) -> bool {
+        !self.state.invalid.load(Ordering::Relaxed)
+    }
+
+    /// Check the contents of the blocks with `check` once all `len` bytes are stored,
+    /// which may be immediately, and invalidate the blocks if it returns `false`.
+    ///
+    /// The check runs on a blocking thread if the last missing bytes are stored from
+    /// within a runtime, or otherwise on the thread which stores them. Only the first
+    /// check set for the blocks is used.
+    pub fn on_complete<F>(&self, len: usize, check: F)
+    where
+        F: Fn(&[Bytes]) -> bool + Send + Sync + 'static,
+    {
+        let _ = self.state.completion.set(Completion {
+            len,
+            checked: AtomicBool::new(false),
+            check: Box::new(check),
+        });
+
+        self.check_complete();
+    }
+
+    /// Check the contents of the blocks again, if they have been completed, invalidating
+    /// them if the check fails.
+    ///
+    /// Returns [`None`] if the blocks have not been completed, or if stored bytes have
+    /// since been evicted. Otherwise, returns whether the blocks are valid.
+    pub fn verify(&self) -> Option<bool> {
+        let completion = self.state.completion.get()?;
+        if !completion.checked.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let body = self.read(completion.len)?;
+        if !(completion.check)(&body) {
+            self.invalidate();
+        }
+
+        Some(self.is_valid())
+    }
+
+    /// Check the contents of the blocks if they have just been completed.
+    ///
+    /// Checks read and usually hash the whole body, so when called from within a runtime,
+    /// they run on a blocking thread rather than the task which completed the blocks.
+    fn check_complete(&self) {
+        let Some(completion) = self.state.completion.get() else {
+            return;
+        };
+
+        if completion.checked.load(Ordering::Acquire) || !self.holes(0..completion.len).is_empty() {
+            return;
+        }
+
+        // Only one task checks the blocks when completed concurrently.
+        if completion.checked.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let blocks = self.clone();
+        match Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(move || blocks.check())),
+            Err(_) => blocks.check(),
+        }
+    }
+
+    /// Check the contents of the completed blocks, invalidating them if the check fails.
+    fn check(&self) {
+        let Some(completion) = self.state.completion.get() else {
+            return;
+        };
+
+        let Some(body) = self.read(completion.len) else {
+            // Bytes were evicted before they could be read, so check them once they are
+            // stored again.
+            completion.checked.store(false, Ordering::Release);
+            return;
+        };
+
+        if !(completion.check)(&body) {
+            tracing::warn!("stored body failed verification once complete, invalidating");
+            self.invalidate();
+        }
+    }
+
+    /// Read the first `len` bytes, or return [`None`] if any of them are not stored.
+    fn read(&self, len: usize) -> Option<Vec<Bytes>> {
+        let mut body = Vec::new();
+        let mut offset = 0;
+
+        while offset < len {
+            let bytes = self.get(offset, len - offset).filter(|b| !b.is_empty())?;
+            offset += bytes.len();
+            body.push(bytes);
+        }
+
+        Some(body)
+    }
+}
+
+impl Default for Blocks {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStorage::default()))
     }
 }
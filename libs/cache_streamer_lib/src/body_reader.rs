@@ -2,6 +2,7 @@ use crate::blocks::Blocks;
 use crate::types::*;
 
 use bytes::Bytes;
+use core::ops::Range;
 use futures::{stream, Stream, StreamExt};
 use std::sync::Arc;
 
@@ -36,67 +37,152 @@ impl BlockBodyReader {
 
 /// A simple body reader which tracks an underlying stream and exhausts once the stream
 /// exhausts.
-pub struct StreamBodyReader(BodyStream);
+pub struct StreamBodyReader {
+    stream: BodyStream,
+    overflowed: bool,
+}
 
 impl StreamBodyReader {
     pub fn new(stream: BodyStream) -> Self {
-        Self(stream)
+        Self {
+            stream,
+            overflowed: false,
+        }
     }
 
     /// Attempt to pull bytes from the stream. If bytes can be pulled from the stream,
     /// then the offset is updated, and the bytes are returned. Otherwise, [`None`] is
     /// returned.
     ///
+    /// If the stream continues past `end`, the bytes are truncated at `end`, and any
+    /// later attempt returns an error; see [`StreamBodyReader::overflowed`].
+    ///
     /// The caller is responsible for ensuring `offset < end` before calling this function.
     /// Failure to do so will result in unpredictable behavior.
     pub async fn next(&mut self, offset: &mut usize, end: usize) -> Option<Result<Bytes>> {
         debug_assert!(*offset < end);
 
-        let bytes = match self.0.next().await? {
+        if self.overflowed {
+            return Some(Err(Error::UpstreamProtocol(
+                "upstream body is longer than its declared length".into(),
+            )));
+        }
+
+        let mut bytes = match self.stream.next().await? {
             Ok(bytes) => bytes,
             Err(e) => return Some(Err(e)),
         };
 
+        if bytes.len() > end - *offset {
+            bytes.truncate(end - *offset);
+            self.overflowed = true;
+        }
+
         *offset += bytes.len();
-        assert!(*offset <= end);
 
         Some(Ok(bytes))
     }
+
+    /// Returns whether the stream continued past the end given to
+    /// [`StreamBodyReader::next`].
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
 }
 
 /// A body reader which pipes the results of a body stream into a blocks
 /// object while also returning the results.
+///
+/// The reader tracks the offset of the stream within the body, so that the stream may
+/// start before the requested offset, such as when the upstream server returns the whole
+/// body instead of a range. Bytes before the requested offset are stored but skipped.
+///
+/// Streams which are longer than the declared bounds are truncated, and the blocks are
+/// invalidated, since their contents can no longer be trusted.
 pub struct TeeBodyReader {
     blocks: Blocks,
     stream_reader: StreamBodyReader,
+    position: usize,
+    end: usize,
 }
 
 impl TeeBodyReader {
-    pub fn new(blocks: Blocks, stream: BodyStream) -> Self {
+    /// Create a new tee body reader over a stream of the bytes within `bounds`.
+    pub fn new(blocks: Blocks, stream: BodyStream, bounds: Range<usize>) -> Self {
         Self {
             blocks,
             stream_reader: StreamBodyReader::new(stream),
+            position: bounds.start,
+            end: bounds.end,
         }
     }
 
     /// Attempt to pull bytes from the stream. If bytes can be pulled from the stream,
     /// then the offset is updated, and the bytes are returned. Otherwise, [`None`] is
-    /// returned. The bytes are added to the blocks object at the current offset if
-    /// they are not already present.
+    /// returned. The bytes are added to the blocks object at their offset in the stream
+    /// if they are not already present.
+    ///
+    /// Bytes from the stream before `offset` are skipped, and bytes from the stream past
+    /// `end` are not returned. If the stream is already past `offset`, an error is
+    /// returned.
     ///
     /// The caller is responsible for ensuring `offset < end` before calling this function.
     /// Failure to do so will result in unpredictable behavior.
     pub async fn next(&mut self, offset: &mut usize, end: usize) -> Option<Result<Bytes>> {
-        let current_offset = *offset;
+        debug_assert!(*offset < end);
 
-        Some(
-            self.stream_reader
-                .next(offset, end)
+        loop {
+            let start = self.position;
+            if start > *offset {
+                return Some(Err(Error::CacheInconsistency(
+                    "upstream body starts past the requested offset".into(),
+                )));
+            }
+
+            // The stream has already ended at its declared end.
+            if start >= self.end {
+                return None;
+            }
+
+            let bytes = match self
+                .stream_reader
+                .next(&mut self.position, self.end)
                 .await?
-                .inspect(|bytes| {
-                    self.blocks.put_new(current_offset, bytes.clone());
-                }),
-        )
+            {
+                Ok(bytes) => bytes,
+                Err(e) => return Some(Err(e)),
+            };
+
+            if self.stream_reader.overflowed() && self.blocks.is_valid() {
+                tracing::warn!(
+                    end = self.end,
+                    "upstream body is longer than its declared length, truncating"
+                );
+                self.blocks.invalidate();
+            }
+
+            self.blocks.put_new(start, bytes.clone());
+
+            if self.position <= *offset {
+                continue;
+            }
+
+            let mut bytes = bytes.slice(*offset - start..);
+            bytes.truncate(end - *offset);
+            *offset += bytes.len();
+
+            return Some(Ok(bytes));
+        }
+    }
+
+    /// Returns whether the stream has reached the end of its declared bounds.
+    pub fn is_complete(&self) -> bool {
+        self.position >= self.end
+    }
+
+    /// Consume the tee body reader into the blocks object.
+    pub fn into_inner(self) -> Blocks {
+        self.blocks
     }
 }
 
@@ -108,20 +194,34 @@ async fn make_tee_reader<R>(
 where
     R: Response,
 {
-    let result = match requester.fetch(range).await? {
-        RequesterStatus::Cache(r, ..) => r,
-        RequesterStatus::Passthrough(..) => return Err("invalid upstream status".into()),
+    let (result, response_range) = match requester.fetch(range).await? {
+        RequesterStatus::Cache(r, response_range, ..) => (r, response_range),
+        RequesterStatus::CacheUnknownLength(..) | RequesterStatus::Passthrough(..) => {
+            return Err(Error::CacheInconsistency(
+                "upstream response is no longer cacheable".into(),
+            ))
+        }
     };
 
-    Ok(TeeBodyReader::new(blocks, result.into_body()))
+    // The whole body may be returned instead of the requested range, such as for
+    // responses which do not accept ranges, in which case it is read from the start.
+    Ok(TeeBodyReader::new(
+        blocks,
+        result.into_body(),
+        response_range.bounds(),
+    ))
 }
 
 /// A reader type which tracks a blocks object and a requester, and if the blocks
 /// object exhausts during a pull, makes a new tee body reader covering the remaining
 /// range.
+///
+/// If a body stream ends before its declared length, or fails partway with an upstream
+/// error, such as a stall or a reset connection, a new tee body reader is made to refill
+/// the remaining range.
 pub enum AdaptiveReader<R> {
     Block(Arc<dyn Requester<R>>, BlockBodyReader),
-    Tee(TeeBodyReader),
+    Tee(Arc<dyn Requester<R>>, TeeBodyReader),
     Error,
 }
 
@@ -133,24 +233,63 @@ where
         Self::Block(requester, BlockBodyReader::new(blocks))
     }
 
-    pub fn new_from_body_stream(blocks: Blocks, stream: BodyStream) -> Self {
-        Self::Tee(TeeBodyReader::new(blocks, stream))
+    /// Create a new reader over a body stream which returns the given range.
+    pub fn new_from_body_stream(
+        requester: Arc<dyn Requester<R>>,
+        blocks: Blocks,
+        stream: BodyStream,
+        range: &ResponseRange,
+    ) -> Self {
+        Self::Tee(
+            requester,
+            TeeBodyReader::new(blocks, stream, range.bounds()),
+        )
     }
 
     /// If currently reading blocks, attempts to pull new data from the blocks. If reading
     /// blocks fails, creates a new tee body reader at the current offset. Otherwise, attempts
     /// to pull data from the tee body reader.
     ///
+    /// If the tee body reader ends early or fails with an upstream error, it is dropped to
+    /// abort its request, and a new tee body reader is created at the current offset. If a
+    /// new tee body reader ends or fails before returning any bytes, an error is returned.
+    ///
     /// The caller is responsible for ensuring `offset < end` before calling this function.
     /// Failure to do so will result in unpredictable behavior.
     pub async fn next(&mut self, offset: &mut usize, end: usize) -> Option<Result<Bytes>> {
         // Consume ourself into the error type.
         //
-        // We assume we are going to handle the tee reader case, since it occurs twice,
-        // and handle the other cases internally to this match.
-        let mut tee = match std::mem::replace(self, Self::Error) {
+        // We handle the cases which can continue without a new request internally to
+        // this match, and otherwise fall through to make a new tee reader.
+        let (requester, blocks) = match std::mem::replace(self, Self::Error) {
             Self::Error => return None,
-            Self::Tee(tee) => tee,
+            Self::Tee(requester, mut tee) => match tee.next(offset, end).await {
+                None if !tee.is_complete() => {
+                    tracing::warn!(
+                        offset = *offset,
+                        "upstream body ended before its declared length, refilling"
+                    );
+                    (requester, tee.into_inner())
+                }
+                Some(Err(
+                    e @ (Error::UpstreamConnect(..)
+                    | Error::UpstreamTimeout(..)
+                    | Error::UpstreamProtocol(..)),
+                )) => {
+                    tracing::warn!(
+                        offset = *offset,
+                        error = %e,
+                        "upstream body failed, refilling"
+                    );
+                    (requester, tee.into_inner())
+                }
+                result => {
+                    // Reset error state.
+                    *self = Self::Tee(requester, tee);
+
+                    return result;
+                }
+            },
             Self::Block(requester, reader) => {
                 // Block reader may have bytes available immediately, in which case we
                 // can just return them here.
@@ -161,22 +300,34 @@ where
                     return Some(Ok(bytes));
                 }
 
-                // Build the new tee reader from the input range.
-                let range = RequestRange::FromTo(*offset, end);
-
-                match make_tee_reader(requester, reader.into_inner(), &range).await {
-                    Err(e) => return Some(Err(e)),
-                    Ok(tee) => tee,
-                }
+                (requester, reader.into_inner())
             }
         };
 
-        let result = tee.next(offset, end).await;
+        // Build the new tee reader from the input range.
+        let range = RequestRange::FromTo(*offset, end);
+
+        let mut tee = match make_tee_reader(requester.clone(), blocks, &range).await {
+            Err(e) => return Some(Err(e)),
+            Ok(tee) => tee,
+        };
+
+        // A new request which ends immediately would never make progress, so stay in the
+        // error state rather than making another request.
+        let result = match tee.next(offset, end).await {
+            Some(Ok(bytes)) => bytes,
+            Some(Err(e)) => return Some(Err(e)),
+            None => {
+                return Some(Err(Error::UpstreamProtocol(
+                    "upstream body ended before its declared length".into(),
+                )))
+            }
+        };
 
         // Reset error state.
-        *self = Self::Tee(tee);
+        *self = Self::Tee(requester, tee);
 
-        result
+        Some(Ok(result))
     }
 
     /// Consumes and converts the reader into a stream of `Result<Bytes>`.
@@ -1,9 +1,14 @@
+pub use object_reader::ObjectReader;
 pub use service::Service;
 
 mod blocks;
 mod body_reader;
+mod object_reader;
+mod pending_entry;
 mod response_builder;
 pub mod service;
+pub mod storage;
 #[cfg(test)]
 mod tests;
 pub mod types;
+mod variant_cache;
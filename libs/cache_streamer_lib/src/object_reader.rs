@@ -0,0 +1,155 @@
+use bytes::{Buf, Bytes};
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::blocks::Blocks;
+use crate::body_reader::AdaptiveReader;
+use crate::types::*;
+
+/// A seekable reader over the complete body of a cached response.
+///
+/// Reads are served from the blocks of the response where possible. When a read
+/// encounters a hole, the rest of the body from the current position is fetched
+/// using the requester of the response, filling in the blocks as it goes.
+///
+/// Seeking discards any in-progress fetch, so the next read after a seek will begin
+/// from the blocks again.
+pub struct ObjectReader<R> {
+    requester: Arc<dyn Requester<R>>,
+    blocks: Blocks,
+    size: usize,
+    position: usize,
+    chunk: Bytes,
+    stream: Option<BodyStream>,
+}
+
+impl<R> ObjectReader<R>
+where
+    R: Response,
+{
+    /// Create a new reader positioned at the start of the body.
+    pub(crate) fn new(requester: Arc<dyn Requester<R>>, blocks: Blocks, size: usize) -> Self {
+        Self {
+            requester,
+            blocks,
+            size,
+            position: 0,
+            chunk: Bytes::new(),
+            stream: None,
+        }
+    }
+
+    /// Create a new reader positioned at the start of the body, which will continue
+    /// reading from `stream` until the first seek.
+    ///
+    /// The stream must return the body starting from offset 0.
+    pub(crate) fn with_stream(
+        requester: Arc<dyn Requester<R>>,
+        blocks: Blocks,
+        size: usize,
+        stream: BodyStream,
+    ) -> Self {
+        Self {
+            stream: Some(stream),
+            ..Self::new(requester, blocks, size)
+        }
+    }
+
+    /// Returns the total length of the body in bytes.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Returns whether the body is empty.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Returns the stream of body data at the current offset, creating it if there
+    /// is no stream in progress.
+    fn stream(&mut self) -> &mut BodyStream {
+        let offset = self.position + self.chunk.len();
+
+        self.stream.get_or_insert_with(|| {
+            let reader = AdaptiveReader::new_adaptive(self.requester.clone(), self.blocks.clone());
+            Box::pin(reader.into_stream(offset, self.size))
+        })
+    }
+}
+
+impl<R> AsyncRead for ObjectReader<R>
+where
+    R: Response,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.chunk.is_empty() {
+            if this.position >= this.size || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(this.stream().as_mut().poll_next(cx)) {
+                Some(Ok(bytes)) => this.chunk = bytes,
+                Some(Err(e)) => {
+                    this.stream = None;
+                    return Poll::Ready(Err(io::Error::other(e)));
+                }
+                None => {
+                    this.stream = None;
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+            }
+        }
+
+        let size = this.chunk.len().min(buf.remaining());
+        buf.put_slice(&this.chunk[..size]);
+        this.chunk.advance(size);
+        this.position += size;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R> AsyncSeek for ObjectReader<R>
+where
+    R: Response,
+{
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+
+        let (base, offset) = match position {
+            SeekFrom::Start(offset) => (0, i64::try_from(offset).ok()),
+            SeekFrom::End(offset) => (this.size, Some(offset)),
+            SeekFrom::Current(offset) => (this.position, Some(offset)),
+        };
+
+        let position = offset
+            .and_then(|offset| i64::try_from(base).ok()?.checked_add(offset))
+            .and_then(|position| usize::try_from(position).ok())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        if position >= this.position && position - this.position <= this.chunk.len() {
+            // Still inside of the current chunk, so keep the stream.
+            this.chunk.advance(position - this.position);
+        } else {
+            this.chunk.clear();
+            this.stream = None;
+        }
+
+        this.position = position;
+
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position as u64))
+    }
+}
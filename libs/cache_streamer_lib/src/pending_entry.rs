@@ -0,0 +1,148 @@
+use parking_lot::Mutex;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+
+use crate::blocks::Blocks;
+use crate::response_builder::ResponseBuilder;
+use crate::types::*;
+use crate::variant_cache::VariantCache;
+
+/// An item whose length was not known when it was fetched, which is inserted into the
+/// cache once its body has been read completely.
+pub struct PendingEntry<K, R>
+where
+    K: Ord + 'static,
+    R: Response,
+{
+    pub cache: Arc<VariantCache<K, R>>,
+    pub storage: Arc<dyn StorageBackend>,
+    pub requester: Arc<dyn Requester<R>>,
+    pub time: R::Timepoint,
+    pub key: K,
+    pub request: R::RequestData,
+    pub max_len: usize,
+    pub expire_time: Option<R::Timepoint>,
+    pub data: R::Data,
+    pub pending: PendingKeys<K>,
+}
+
+/// The keys of items whose body is being collected by a [`PendingEntry`].
+pub type PendingKeys<K> = Arc<Mutex<BTreeSet<K>>>;
+
+impl<K, R> PendingEntry<K, R>
+where
+    K: Ord + Clone + Send + Sync + 'static,
+    R: Response,
+{
+    /// Pass through the body of `response`, keeping it as it is read, and insert the item
+    /// into the cache when the body ends.
+    ///
+    /// Only one request collects the body of each key at a time, and the body is passed
+    /// through unchanged for the others. Nothing is inserted if the body fails, is
+    /// dropped before it ends, or exceeds the maximum length.
+    pub fn tee(self, response: R) -> R {
+        let Some(claim) = Claim::new(&self.pending, &self.key) else {
+            return response;
+        };
+
+        response.map_body(|body| Box::pin(self.collect(body, claim)))
+    }
+
+    /// Pass through `body`, keeping its chunks, and finish the item when it ends.
+    fn collect(self, body: BodyStream, claim: Claim<K>) -> impl Stream<Item = Result<Bytes>> {
+        let state = (body, 0, Some((self, Vec::new(), claim)));
+
+        stream::unfold(state, |(mut body, offset, mut pending)| async move {
+            match body.next().await {
+                Some(Ok(bytes)) => {
+                    let len = offset + bytes.len();
+
+                    match &mut pending {
+                        Some((entry, chunks, _)) if len <= entry.max_len => {
+                            chunks.push(bytes.clone())
+                        }
+                        _ => pending = None,
+                    }
+
+                    Some((Ok(bytes), (body, len, pending)))
+                }
+                Some(Err(e)) => Some((Err(e), (body, offset, None))),
+                None => {
+                    // The claim is released once the item has been inserted.
+                    if let Some((entry, chunks, _claim)) = pending {
+                        entry.finish(chunks, offset);
+                    }
+
+                    None
+                }
+            }
+        })
+    }
+
+    /// Insert the item, whose complete body of `len` bytes is `chunks`, into the cache.
+    ///
+    /// The length is only known once the body ends, so the chunks are kept in memory
+    /// until then and copied into storage created for that length. If storage cannot be
+    /// created or any of the body could not be stored, the item is not cached.
+    fn finish(self, chunks: Vec<Bytes>, len: usize) {
+        let storage = match self.storage.create_for_len(len) {
+            Ok(storage) => storage,
+            Err(e) => {
+                tracing::warn!(len, error = %e, "failed to create storage, not caching the body");
+                return;
+            }
+        };
+
+        let blocks = Blocks::new(storage);
+        let mut offset = 0;
+        for bytes in chunks {
+            let next = offset + bytes.len();
+            blocks.put_new(offset, bytes);
+            offset = next;
+        }
+
+        if !blocks.holes(0..len).is_empty() {
+            return;
+        }
+
+        let range = ResponseRange {
+            bytes_len: len,
+            bytes_range: RequestRange::None,
+        };
+        let item = ResponseBuilder::new_empty(&range, self.data, self.requester, blocks);
+
+        self.cache.insert(
+            &self.time,
+            &self.key,
+            &self.request,
+            len,
+            self.expire_time,
+            item,
+        );
+    }
+}
+
+/// A claim on collecting the body of the item with a key, released when dropped.
+struct Claim<K: Ord> {
+    keys: PendingKeys<K>,
+    key: K,
+}
+
+impl<K: Ord + Clone> Claim<K> {
+    /// Claim `key`, or return [`None`] if it has already been claimed.
+    fn new(keys: &PendingKeys<K>, key: &K) -> Option<Self> {
+        keys.lock().insert(key.clone()).then(|| Self {
+            keys: keys.clone(),
+            key: key.clone(),
+        })
+    }
+}
+
+impl<K: Ord> Drop for Claim<K> {
+    fn drop(&mut self) {
+        self.keys.lock().remove(&self.key);
+    }
+}
@@ -1,7 +1,12 @@
-use std::sync::Arc;
+use std::num::NonZeroU64;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, OnceLock};
+
+use range_header::ByteRangeSpec;
 
 use crate::blocks::Blocks;
 use crate::body_reader::AdaptiveReader;
+use crate::object_reader::ObjectReader;
 use crate::types::*;
 
 /// Builder for response data based on a requester and template response.
@@ -12,6 +17,7 @@ where
     requester: Arc<dyn Requester<R>>,
     size: usize,
     data: R::Data,
+    completed_data: Arc<OnceLock<R::Data>>,
     blocks: Blocks,
 }
 
@@ -20,28 +26,72 @@ where
     R: Response,
 {
     /// Create a new builder based on a template response, then return self and a response
-    /// created from the builder and the input response stream.
+    /// for `request_range` created from the builder and the input response stream.
+    ///
+    /// The body of the response, which covers `range`, will be stored into `blocks`. It
+    /// may cover more than the requested range, such as the whole body, in which case
+    /// the bytes outside of the requested range are stored but not streamed.
     pub fn new(
         response: R,
         range: &ResponseRange,
+        request_range: &RequestRange,
         data: R::Data,
         requester: Arc<dyn Requester<R>>,
+        blocks: Blocks,
     ) -> Result<(R, Self)> {
-        let this = Self {
-            requester,
-            size: range.bytes_len,
-            data,
-            blocks: Blocks::default(),
-        };
+        let this = Self::new_empty(range, data, requester, blocks);
 
         let blocks = this.blocks.clone();
-        let reader = AdaptiveReader::new_from_body_stream(blocks, response.into_body());
+        let requester = this.requester.clone();
+        let reader =
+            AdaptiveReader::new_from_body_stream(requester, blocks, response.into_body(), range);
 
-        Ok((this.stream_with_reader(&range.bytes_range, reader)?, this))
+        Ok((this.stream_with_reader(request_range, reader)?, this))
+    }
+
+    /// Create a new builder for a response whose body has not been fetched yet.
+    ///
+    /// The body will be fetched into `blocks` as it is streamed. Once it has all been
+    /// stored, it is verified with [`Response::verify_body`], and the data is updated
+    /// with [`Response::complete_data`].
+    pub fn new_empty(
+        range: &ResponseRange,
+        data: R::Data,
+        requester: Arc<dyn Requester<R>>,
+        blocks: Blocks,
+    ) -> Self {
+        let completed_data = Arc::new(OnceLock::new());
+
+        let verify_data = data.clone();
+        let verify_completed_data = completed_data.clone();
+        blocks.on_complete(range.bytes_len, move |body| {
+            if !R::verify_body(&verify_data, body) {
+                return false;
+            }
+
+            if let Some(data) = R::complete_data(&verify_data, body) {
+                let _ = verify_completed_data.set(data);
+            }
+
+            true
+        });
+
+        Self {
+            requester,
+            size: range.bytes_len,
+            data,
+            completed_data,
+            blocks,
+        }
     }
 
     /// Create a new response which streams body data from the given request range.
-    /// If the request range is invalid, it is clipped to the underlying size of the body.
+    /// If the request range extends past the end of the body, it is clipped to the
+    /// underlying size of the body. If it starts past the end of the body,
+    /// [`Error::UnsatisfiableRange`] is returned.
+    ///
+    /// If the response does not accept ranges, the request range is ignored; see
+    /// [`Response::accepts_ranges`].
     pub fn stream(&self, range: &RequestRange) -> Result<R> {
         let blocks = self.blocks.clone();
         let requester = self.requester.clone();
@@ -49,9 +99,72 @@ where
         self.stream_with_reader(range, AdaptiveReader::new_adaptive(requester, blocks))
     }
 
+    /// Get the data of the response, which is updated once the whole body is stored.
+    pub fn data(&self) -> &R::Data {
+        self.completed_data.get().unwrap_or(&self.data)
+    }
+
+    /// Returns whether all bytes of the given request range are stored, so that
+    /// streaming it will not make any requests.
+    pub fn is_stored(&self, range: &RequestRange) -> Result<bool> {
+        let (start, end) = match R::accepts_ranges(self.data()) {
+            true => get_start_and_end(self.size, range)?,
+            false => (0, self.size),
+        };
+
+        Ok(self.blocks.holes(start..end).is_empty())
+    }
+
+    /// Returns whether the stored body is still valid. Items with invalid bodies should
+    /// no longer be served.
+    pub fn is_valid(&self) -> bool {
+        self.blocks.is_valid()
+    }
+
+    /// Verify the stored body again with [`Response::verify_body`], invalidating it if
+    /// it is no longer intact.
+    ///
+    /// Returns [`None`] if the body has not been completely stored.
+    pub fn verify(&self) -> Option<bool> {
+        self.blocks.verify()
+    }
+
+    /// Create a new seekable reader over the body.
+    pub fn reader(&self) -> ObjectReader<R> {
+        ObjectReader::new(self.requester.clone(), self.blocks.clone(), self.size)
+    }
+
+    /// Create a new seekable reader over the body, which reads from the body of
+    /// `response` until the first seek.
+    ///
+    /// The response must have been created by this builder for the entire body.
+    pub fn reader_with_response(&self, response: R) -> ObjectReader<R> {
+        ObjectReader::with_stream(
+            self.requester.clone(),
+            self.blocks.clone(),
+            self.size,
+            response.into_body(),
+        )
+    }
+
     /// Create a new response from the template data given a range and a reader.
+    ///
+    /// If the response does not accept ranges, the entire body is used instead.
     fn stream_with_reader(&self, range: &RequestRange, reader: AdaptiveReader<R>) -> Result<R> {
-        let (start, end) = get_start_and_end(self.size, range);
+        if !R::accepts_ranges(self.data()) {
+            let range = ResponseRange {
+                bytes_len: self.size,
+                bytes_range: RequestRange::None,
+            };
+
+            return R::from_parts(
+                self.data().clone(),
+                range,
+                Box::pin(reader.into_stream(0, self.size)),
+            );
+        }
+
+        let (start, end) = get_start_and_end(self.size, range)?;
 
         let range = ResponseRange {
             bytes_len: self.size,
@@ -59,20 +172,70 @@ where
         };
 
         R::from_parts(
-            self.data.clone(),
+            self.data().clone(),
             range,
             Box::pin(reader.into_stream(start, end)),
         )
     }
 }
 
+impl<R> Clone for ResponseBuilder<R>
+where
+    R: Response,
+{
+    fn clone(&self) -> Self {
+        Self {
+            requester: self.requester.clone(),
+            size: self.size,
+            data: self.data.clone(),
+            completed_data: self.completed_data.clone(),
+            blocks: self.blocks.clone(),
+        }
+    }
+}
+
 /// Find the bounded byte range of the given potentially unbounded request range,
 /// given the overall size of a file.
-fn get_start_and_end(size: usize, range: &RequestRange) -> (usize, usize) {
-    match *range {
-        RequestRange::None => (0, size),
-        RequestRange::AllFrom(start) => (start.min(size), size),
-        RequestRange::Last(count) => (size - count.min(size), size),
-        RequestRange::FromTo(start, end) => (start.min(size), end.min(size)),
-    }
+///
+/// Following [RFC 7233 section 2.1](https://tools.ietf.org/html/rfc7233#section-2.1),
+/// ranges which end past the end of the file are clipped to the end of the file, and
+/// [`Error::UnsatisfiableRange`] is returned for ranges which start at or past the end
+/// of the file, including any suffix range of an empty file and any empty suffix range,
+/// as per RFC 9110 section 14.1.1. Ranges are checked with
+/// [`ByteRangeSpec::to_satisfiable_range_bounds`], as for `range` headers.
+fn get_start_and_end(size: usize, range: &RequestRange) -> Result<(usize, usize)> {
+    let unsatisfiable = || Error::UnsatisfiableRange(size);
+
+    // Byte range specs are end-inclusive, so empty ranges within the file are handled here.
+    let spec = match *range {
+        RequestRange::None => return Ok((0, size)),
+        RequestRange::FromTo(start, end) if start == end && start < size => {
+            return Ok((start, end))
+        }
+        RequestRange::AllFrom(start) => ByteRangeSpec::AllFrom(start as u64),
+        RequestRange::Last(count) => {
+            ByteRangeSpec::Last(NonZeroU64::new(count as u64).ok_or_else(unsatisfiable)?)
+        }
+        RequestRange::FromTo(start, end) => {
+            let last = end.checked_sub(1).ok_or_else(unsatisfiable)?;
+            ByteRangeSpec::FromTo(start as u64, last as u64)
+        }
+    };
+
+    let bounds = spec
+        .to_satisfiable_range_bounds(size as u64)
+        .map_err(|_| unsatisfiable())?;
+
+    let start = match bounds.start_bound() {
+        Bound::Included(&start) => start,
+        Bound::Excluded(&start) => start + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match bounds.end_bound() {
+        Bound::Included(&end) => end + 1,
+        Bound::Excluded(&end) => end,
+        Bound::Unbounded => size as u64,
+    };
+
+    Ok((start as usize, end as usize))
 }
@@ -1,9 +1,12 @@
-use parking_lot::Mutex;
 use std::sync::Arc;
 
+use crate::blocks::Blocks;
+use crate::object_reader::ObjectReader;
+use crate::pending_entry::{PendingEntry, PendingKeys};
 use crate::response_builder::ResponseBuilder;
+use crate::storage::MemoryStorageBackend;
 use crate::types::*;
-use sized_ttl_cache::{Entry, SizedTTLCache};
+use crate::variant_cache::VariantCache;
 
 // Main service for cache streamer.
 pub struct Service<K, R>
@@ -12,61 +15,258 @@ where
     R: Response,
 {
     backend: Arc<dyn RequestBackend<K, R>>,
-    cache: Mutex<SizedTTLCache<K, R::Timepoint, ResponseBuilder<R>>>,
+    storage: Arc<dyn StorageBackend>,
+    cache: Arc<VariantCache<K, R>>,
+    pending: PendingKeys<K>,
 }
 
+/// The default maximum number of variants stored for each key.
+const DEFAULT_MAX_VARIANTS: usize = 16;
+
 impl<K, R> Service<K, R>
 where
-    K: Ord + 'static,
+    K: Ord + Clone + Send + Sync + 'static,
     R: Response,
 {
+    /// Create a new [`Service`] which stores response bodies in memory.
     pub fn new(backend: Arc<dyn RequestBackend<K, R>>, cache_capacity: usize) -> Self {
+        Self::with_storage(backend, Arc::new(MemoryStorageBackend), cache_capacity)
+    }
+
+    /// Create a new [`Service`] which stores response bodies in storage created
+    /// by the given [`StorageBackend`].
+    pub fn with_storage(
+        backend: Arc<dyn RequestBackend<K, R>>,
+        storage: Arc<dyn StorageBackend>,
+        cache_capacity: usize,
+    ) -> Self {
         Self {
             backend,
-            cache: Mutex::new(SizedTTLCache::with_capacity(cache_capacity)),
+            storage,
+            cache: Arc::new(VariantCache::new(cache_capacity, DEFAULT_MAX_VARIANTS)),
+            pending: PendingKeys::default(),
         }
     }
 
+    /// Set the maximum number of variants stored for each key, for responses which vary
+    /// based on the request. When a new variant is stored, the oldest variant is evicted.
+    ///
+    /// At least one variant is always stored.
+    pub fn with_max_variants(self, max_variants: usize) -> Self {
+        self.cache.set_max_variants(max_variants);
+        self
+    }
+
     /// Get a response with the given current time, request key, and request range.
+    ///
+    /// `request` selects the variant of the item, and is used to create a requester when
+    /// the item is not in the cache; see [`RequestBackend::create_for_key`].
     pub async fn call(
         &self,
         time: &R::Timepoint,
         key: &K,
         range: &RequestRange,
-    ) -> Result<ServiceStatus<R>>
-    where
-        K: ToOwned<Owned = K>,
-    {
+        request: &R::RequestData,
+    ) -> Result<ServiceStatus<R>> {
         // Try to get the item from cache.
         //
         // The cache may also contain partial items which have not finished streaming yet.
         // This is fine, because our response will fetch unfinished bytes and continue
         // to feed the stream.
-        if let Some(item) = self.cache.lock().get(time, key) {
-            return Ok(ServiceStatus::Cache(item.stream(range)?));
+        if let Some((insertion_time, item)) = self.cache.lookup(time, key, request) {
+            let status = match item.is_stored(range)? {
+                true => CacheStatus::Hit,
+                false => CacheStatus::PartialHit,
+            };
+
+            let detail = CacheDetail {
+                status,
+                insertion_time,
+            };
+
+            return Ok(ServiceStatus::Cache(item.stream(range)?, detail));
         }
 
         // The item was not in the cache, so make a request.
-        let requester = self.backend.create_for_key(key);
+        match self.fetch(time, key, range, request).await? {
+            Fetched::Cache(response, _) => Ok(ServiceStatus::Cache(response, miss(time))),
+            Fetched::Passthrough(response) => Ok(ServiceStatus::Passthrough(response)),
+        }
+    }
+
+    /// Get a response with the given current time, request key, and request range, for
+    /// which only the metadata is needed. The body of the response should not be read.
+    ///
+    /// Unlike [`Service::call`], this will not fetch the body when the item is not in
+    /// the cache. A cacheable response will be inserted into the cache without any body
+    /// data, which will be fetched when the item is next requested with [`Service::call`].
+    pub async fn call_metadata(
+        &self,
+        time: &R::Timepoint,
+        key: &K,
+        range: &RequestRange,
+        request: &R::RequestData,
+    ) -> Result<ServiceStatus<R>> {
+        // The body of the cached response is never read, so nothing is fetched.
+        if let Some((insertion_time, item)) = self.cache.lookup(time, key, request) {
+            let detail = CacheDetail {
+                status: CacheStatus::Hit,
+                insertion_time,
+            };
+
+            return Ok(ServiceStatus::Cache(item.stream(range)?, detail));
+        }
+
+        let requester = self.backend.create_for_key(key, request);
+
+        let (response, response_range, expire_time, data) = match requester.fetch_metadata().await?
+        {
+            RequesterStatus::Cache(response, range, expire_time, data) => {
+                (response, range, expire_time, data)
+            }
+            // Without the body, the length remains unknown, so nothing can be cached.
+            RequesterStatus::CacheUnknownLength(r, ..) | RequesterStatus::Passthrough(r) => {
+                return Ok(ServiceStatus::Passthrough(r))
+            }
+        };
+
+        let Some(storage) = self.create_storage(response_range.bytes_len) else {
+            return Ok(ServiceStatus::Passthrough(response));
+        };
+        let blocks = Blocks::new(storage);
+        let item = ResponseBuilder::new_empty(&response_range, data, requester, blocks);
+        let response = item.stream(range)?;
+
+        // Insert the new builder into the cache.
+        self.cache.insert(
+            time,
+            key,
+            request,
+            response_range.bytes_len,
+            expire_time,
+            item,
+        );
+
+        Ok(ServiceStatus::Cache(response, miss(time)))
+    }
+
+    /// Get a seekable reader over the complete response with the given current time,
+    /// request key, and request data.
+    ///
+    /// Returns [`None`] if the response was not cacheable, as there is nothing to
+    /// seek within.
+    pub async fn open(
+        &self,
+        time: &R::Timepoint,
+        key: &K,
+        request: &R::RequestData,
+    ) -> Result<Option<ObjectReader<R>>> {
+        if let Some((_, item)) = self.cache.lookup(time, key, request) {
+            return Ok(Some(item.reader()));
+        }
+
+        // The reader will continue from the fetched response until it seeks.
+        match self.fetch(time, key, &RequestRange::None, request).await? {
+            Fetched::Cache(response, item) => Ok(Some(item.reader_with_response(response))),
+            Fetched::Passthrough(..) => Ok(None),
+        }
+    }
+
+    /// Verify the stored bodies of all complete items in the cache, and evict items whose
+    /// body is no longer intact, such as from memory corruption; see
+    /// [`Response::verify_body`]. Returns the number of evicted items.
+    ///
+    /// This reads every stored body, so it should be called periodically from a
+    /// blocking task.
+    pub fn scrub(&self) -> usize {
+        self.cache.scrub()
+    }
+
+    /// Make a request for an item which is not in the cache, and insert it into the
+    /// cache if it is cacheable.
+    async fn fetch(
+        &self,
+        time: &R::Timepoint,
+        key: &K,
+        range: &RequestRange,
+        request: &R::RequestData,
+    ) -> Result<Fetched<R>> {
+        let requester = self.backend.create_for_key(key, request);
 
         // Even if the request is potentially cacheable, we only cache requests that return
         // some form of valid response range. Without this, we can't support suffix queries
         // correctly.
-        let (response, range, expire_time, data) = match requester.fetch(range).await? {
-            RequesterStatus::Cache(response, range, expire_time, data) => {
-                (response, range, expire_time, data)
+        let (response, response_range, expire_time, data) = match requester.fetch(range).await? {
+            RequesterStatus::Cache(response, response_range, expire_time, data) => {
+                (response, response_range, expire_time, data)
+            }
+            RequesterStatus::CacheUnknownLength(response, max_len, expire_time, data) => {
+                // The item is only inserted once the whole body has been read, so until
+                // then, it is passed through, and other requests for it will fetch it too,
+                // although only one of them stores the body.
+                let pending = PendingEntry {
+                    cache: self.cache.clone(),
+                    storage: self.storage.clone(),
+                    requester,
+                    time: time.clone(),
+                    key: key.clone(),
+                    request: request.clone(),
+                    max_len,
+                    expire_time,
+                    data,
+                    pending: self.pending.clone(),
+                };
+
+                return Ok(Fetched::Passthrough(pending.tee(response)));
             }
-            RequesterStatus::Passthrough(r) => return Ok(ServiceStatus::Passthrough(r)),
+            RequesterStatus::Passthrough(r) => return Ok(Fetched::Passthrough(r)),
         };
 
         // The response builder will return a stream here built from the current response,
         // avoiding the need to make a second request.
-        let (stream, item) = ResponseBuilder::new(response, &range, data, requester)?;
+        //
+        // The response may cover more than the requested range, such as when the upstream
+        // server ignores ranges, in which case only the requested range is streamed.
+        let len = response_range.bytes_len;
+        let Some(storage) = self.create_storage(len) else {
+            return Ok(Fetched::Passthrough(response));
+        };
+        let blocks = Blocks::new(storage);
+        let (stream, item) =
+            ResponseBuilder::new(response, &response_range, range, data, requester, blocks)?;
 
         // Insert the new builder into the cache.
-        let entry = Entry::from_parts(range.bytes_len, expire_time, item);
-        self.cache.lock().get_or_insert(time, key, entry);
+        self.cache
+            .insert(time, key, request, len, expire_time, item.clone());
+
+        Ok(Fetched::Cache(stream, item))
+    }
+
+    /// Create storage for a body of `len` bytes, or return [`None`] if it cannot be
+    /// created, in which case the response is passed through rather than failing.
+    fn create_storage(&self, len: usize) -> Option<Arc<dyn Storage>> {
+        self.storage
+            .create_for_len(len)
+            .inspect_err(|e| {
+                tracing::warn!(len, error = %e, "failed to create storage, not caching the body")
+            })
+            .ok()
+    }
+}
 
-        Ok(ServiceStatus::Cache(stream))
+/// Details for a response which was fetched and inserted into the cache at `time`.
+fn miss<T: Clone>(time: &T) -> CacheDetail<T> {
+    CacheDetail {
+        status: CacheStatus::Miss,
+        insertion_time: time.clone(),
     }
 }
+
+/// The result of fetching an item which was not in the cache.
+enum Fetched<R: Response> {
+    /// The response was cached using the given builder.
+    Cache(R, ResponseBuilder<R>),
+
+    /// The response was passed through.
+    Passthrough(R),
+}
@@ -0,0 +1,13 @@
+//! [`Storage`] implementations for cached response bodies.
+//!
+//! [`Storage`]: crate::types::Storage
+
+#[cfg(unix)]
+pub use file::{FileStorage, FileStorageBackend};
+pub use memory::{MemoryStorage, MemoryStorageBackend};
+pub use mmap::{MmapArena, MmapStorage};
+
+#[cfg(unix)]
+mod file;
+mod memory;
+mod mmap;
@@ -0,0 +1,96 @@
+use bytes::{Bytes, BytesMut};
+use core::ops::Range;
+use parking_lot::RwLock;
+use sparse_map::SparseMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::types::*;
+
+/// [`Storage`] which keeps bytes in an anonymous sparse file.
+///
+/// The file is unlinked on creation, so its disk space is reclaimed once the
+/// storage is dropped. Evicted ranges are forgotten, but remain allocated on disk
+/// until then.
+///
+/// Reads and writes block the calling thread, which is usually an async worker, as
+/// [`Storage`] is synchronous. Each one covers a single chunk of a body and is
+/// normally served by the page cache, and creating the file does not allocate its
+/// blocks, so this is comparable to the page faults of [`MmapStorage`]. A slow disk
+/// will stall the worker threads, in which case memory storage is preferable.
+///
+/// [`MmapStorage`]: super::MmapStorage
+pub struct FileStorage {
+    file: File,
+    mapped: RwLock<SparseMap<usize>>,
+}
+
+impl FileStorage {
+    /// Create a new [`FileStorage`] for `len` bytes inside of `directory`.
+    pub fn new_in(directory: &Path, len: usize) -> Result<Self> {
+        let file = tempfile::tempfile_in(directory)?;
+        file.set_len(len.try_into().map_err(|e| Error::Storage(Box::new(e)))?)?;
+
+        Ok(Self {
+            file,
+            mapped: RwLock::default(),
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&self, offset: usize, max_size: usize) -> Option<Bytes> {
+        let mapped = self.mapped.read();
+        let size = mapped.get(offset, max_size)?;
+
+        let mut buf = BytesMut::zeroed(size);
+        self.file.read_exact_at(&mut buf, offset as u64).ok()?;
+
+        Some(buf.freeze())
+    }
+
+    fn put(&self, offset: usize, data: Bytes) {
+        let mut mapped = self.mapped.write();
+        let holes: Vec<_> = mapped.holes(offset..(offset + data.len())).collect();
+
+        for hole in holes {
+            let bytes = &data[(hole.start - offset)..(hole.end - offset)];
+
+            // Only mark the range as mapped once it is known to be written.
+            if self.file.write_all_at(bytes, hole.start as u64).is_ok() {
+                mapped.put_new(hole.start, hole.len());
+            }
+        }
+    }
+
+    fn holes(&self, range: Range<usize>) -> Vec<Range<usize>> {
+        self.mapped.read().holes(range).collect()
+    }
+
+    fn evict(&self, range: Range<usize>) -> usize {
+        self.mapped.write().remove(range)
+    }
+}
+
+/// [`StorageBackend`] which creates a [`FileStorage`] for each response inside of
+/// a fixed directory.
+pub struct FileStorageBackend {
+    directory: PathBuf,
+}
+
+impl FileStorageBackend {
+    /// Create a new [`FileStorageBackend`] which places files in `directory`.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+}
+
+impl StorageBackend for FileStorageBackend {
+    fn create_for_len(&self, len: usize) -> Result<Arc<dyn Storage>> {
+        Ok(Arc::new(FileStorage::new_in(&self.directory, len)?))
+    }
+}
@@ -0,0 +1,42 @@
+use bytes::Bytes;
+use core::ops::Range;
+use parking_lot::RwLock;
+use sparse_map::SparseMap;
+use std::sync::Arc;
+
+use crate::types::*;
+
+/// [`Storage`] which keeps bytes in memory in a sparse map.
+///
+/// This does not copy data which is put into it, so views of larger buffers are
+/// retained until the storage is dropped.
+#[derive(Default)]
+pub struct MemoryStorage(RwLock<SparseMap<Bytes>>);
+
+impl Storage for MemoryStorage {
+    fn get(&self, offset: usize, max_size: usize) -> Option<Bytes> {
+        self.0.read().get(offset, max_size)
+    }
+
+    fn put(&self, offset: usize, data: Bytes) {
+        self.0.write().put_new(offset, data)
+    }
+
+    fn holes(&self, range: Range<usize>) -> Vec<Range<usize>> {
+        self.0.read().holes(range).collect()
+    }
+
+    fn evict(&self, range: Range<usize>) -> usize {
+        self.0.write().remove(range)
+    }
+}
+
+/// [`StorageBackend`] which creates [`MemoryStorage`].
+#[derive(Default)]
+pub struct MemoryStorageBackend;
+
+impl StorageBackend for MemoryStorageBackend {
+    fn create_for_len(&self, _len: usize) -> Result<Arc<dyn Storage>> {
+        Ok(Arc::new(MemoryStorage::default()))
+    }
+}
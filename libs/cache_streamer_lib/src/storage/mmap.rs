@@ -0,0 +1,128 @@
+use bytes::Bytes;
+use core::ops::Range;
+use memmap2::MmapMut;
+use parking_lot::{Mutex, RwLock};
+use sparse_map::SparseMap;
+use std::sync::Arc;
+
+use crate::types::*;
+
+/// [`StorageBackend`] which allocates a [`MmapStorage`] for each response from a
+/// single fixed-size anonymous memory map.
+///
+/// The arena does not grow, so creating storage fails once there is no contiguous
+/// free space left for a response. Responses which are still being streamed keep
+/// their space allocated after they are evicted from the cache, so the capacity
+/// should exceed the capacity of the cache.
+pub struct MmapArena(Arc<Arena>);
+
+struct Arena {
+    map: RwLock<MmapMut>,
+    allocated: Mutex<SparseMap<usize>>,
+}
+
+impl MmapArena {
+    /// Create a new [`MmapArena`] which holds up to `capacity` bytes.
+    pub fn with_capacity(capacity: usize) -> Result<Self> {
+        let arena = Arena {
+            map: RwLock::new(MmapMut::map_anon(capacity.max(1))?),
+            allocated: Mutex::default(),
+        };
+
+        Ok(Self(Arc::new(arena)))
+    }
+}
+
+impl Arena {
+    /// Find the first free extent of `len` bytes and mark it as allocated.
+    fn allocate(&self, len: usize) -> Option<Range<usize>> {
+        if len == 0 {
+            return Some(0..0);
+        }
+
+        let capacity = self.map.read().len();
+        let mut allocated = self.allocated.lock();
+
+        let start = allocated
+            .holes(0..capacity)
+            .find(|hole| hole.len() >= len)?
+            .start;
+
+        allocated.put_new(start, len);
+
+        Some(start..(start + len))
+    }
+
+    fn release(&self, extent: Range<usize>) {
+        self.allocated.lock().remove(extent);
+    }
+}
+
+impl StorageBackend for MmapArena {
+    fn create_for_len(&self, len: usize) -> Result<Arc<dyn Storage>> {
+        let extent = self
+            .0
+            .allocate(len)
+            .ok_or_else(|| Error::Storage("mmap arena is full".into()))?;
+
+        Ok(Arc::new(MmapStorage {
+            arena: self.0.clone(),
+            extent,
+            mapped: RwLock::default(),
+        }))
+    }
+}
+
+/// [`Storage`] which keeps bytes in an extent of a [`MmapArena`].
+///
+/// The extent is returned to the arena once the storage is dropped.
+pub struct MmapStorage {
+    arena: Arc<Arena>,
+    extent: Range<usize>,
+    mapped: RwLock<SparseMap<usize>>,
+}
+
+impl Storage for MmapStorage {
+    fn get(&self, offset: usize, max_size: usize) -> Option<Bytes> {
+        let mapped = self.mapped.read();
+        let size = mapped.get(offset, max_size)?;
+        let start = self.extent.start + offset;
+
+        Some(Bytes::copy_from_slice(
+            &self.arena.map.read()[start..(start + size)],
+        ))
+    }
+
+    fn put(&self, offset: usize, data: Bytes) {
+        // Discard anything which would be written past the end of the extent.
+        let end = (offset + data.len()).min(self.extent.len());
+        if offset >= end {
+            return;
+        }
+
+        let mut mapped = self.mapped.write();
+        let holes: Vec<_> = mapped.holes(offset..end).collect();
+
+        for hole in holes {
+            let bytes = &data[(hole.start - offset)..(hole.end - offset)];
+            let start = self.extent.start + hole.start;
+
+            self.arena.map.write()[start..(start + bytes.len())].copy_from_slice(bytes);
+            mapped.put_new(hole.start, hole.len());
+        }
+    }
+
+    fn holes(&self, range: Range<usize>) -> Vec<Range<usize>> {
+        self.mapped.read().holes(range).collect()
+    }
+
+    fn evict(&self, range: Range<usize>) -> usize {
+        self.mapped.write().remove(range)
+    }
+}
+
+impl Drop for MmapStorage {
+    fn drop(&mut self) {
+        self.arena.release(self.extent.clone());
+    }
+}
@@ -1,6 +1,7 @@
 use core::sync::atomic::{AtomicUsize, Ordering};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::types::*;
 use bytes::Bytes;
@@ -8,13 +9,30 @@ use futures::{future, stream, Future};
 
 mod blocks;
 mod body_reader;
+mod object_reader;
 mod response_builder;
 mod service;
+mod storage;
+mod variant_cache;
 
 const HELLO_WORLD: &[u8] = b"hello world";
 const GOODBYE: &[u8] = b"goodbye";
 const EXPIRE_TIME: usize = 2;
 
+/// Wait for `condition` to hold, such as once the check of complete blocks has run on
+/// a blocking thread.
+async fn eventually(condition: impl Fn() -> bool) {
+    for _ in 0..100 {
+        if condition() {
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    panic!("condition did not hold");
+}
+
 struct SimpleResponse(BodyStream);
 
 impl SimpleResponse {
@@ -24,8 +42,11 @@ impl SimpleResponse {
 }
 
 impl Response for SimpleResponse {
-    type Data = ();
+    /// The length of the body, known once it has been stored.
+    type Data = Option<usize>;
+    type RequestData = Option<usize>;
     type Timepoint = usize;
+    type VariantKey = usize;
 
     fn from_parts(_data: Self::Data, _range: ResponseRange, body: BodyStream) -> Result<Self> {
         Ok(Self(body))
@@ -34,6 +55,21 @@ impl Response for SimpleResponse {
     fn into_body(self) -> BodyStream {
         self.0
     }
+
+    fn map_body<F>(self, f: F) -> Self
+    where
+        F: FnOnce(BodyStream) -> BodyStream,
+    {
+        Self(f(self.0))
+    }
+
+    fn complete_data(_data: &Self::Data, body: &[Bytes]) -> Option<Self::Data> {
+        Some(Some(body.iter().map(Bytes::len).sum()))
+    }
+
+    fn variant_key(_data: &Self::Data, request: &Self::RequestData) -> Option<Self::VariantKey> {
+        *request
+    }
 }
 
 struct SimpleRequester {
@@ -64,7 +100,7 @@ impl Requester<SimpleResponse> for SimpleRequester {
                     bytes_range: range.clone(),
                 },
                 Some(EXPIRE_TIME),
-                (),
+                None,
             )
         } else {
             RequesterStatus::Passthrough(resp)
@@ -91,7 +127,11 @@ impl SimpleRequestBackend {
 }
 
 impl RequestBackend<String, SimpleResponse> for SimpleRequestBackend {
-    fn create_for_key(&self, _key: &String) -> Arc<dyn Requester<SimpleResponse>> {
+    fn create_for_key(
+        &self,
+        _key: &String,
+        _request: &Option<usize>,
+    ) -> Arc<dyn Requester<SimpleResponse>> {
         Arc::new(SimpleRequester::new(self.count.clone(), self.is_cache))
     }
 }
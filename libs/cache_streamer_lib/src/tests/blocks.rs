@@ -1,3 +1,11 @@
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use super::eventually;
 use crate::blocks::Blocks;
 
 #[test]
@@ -28,3 +36,70 @@ fn test_send_sync() {
 
     assert!(blocks.get(0, 5).is_some());
 }
+
+#[test]
+fn test_invalidate() {
+    let blocks = Blocks::default();
+    let clone = blocks.clone();
+    assert!(blocks.is_valid());
+
+    clone.invalidate();
+    assert!(!blocks.is_valid());
+}
+
+#[test]
+fn test_on_complete() {
+    let blocks = Blocks::default();
+    let checks = Arc::new(AtomicUsize::default());
+
+    let count = checks.clone();
+    blocks.on_complete(11, move |body| {
+        count.fetch_add(1, Ordering::Relaxed);
+        body.concat() == b"hello world"
+    });
+    assert_eq!(blocks.verify(), None);
+
+    // The check is made once, when the last missing bytes are stored.
+    blocks.put_new(6, b"world"[..].into());
+    assert_eq!(checks.load(Ordering::Relaxed), 0);
+    blocks.put_new(0, b"hello "[..].into());
+    assert_eq!(checks.load(Ordering::Relaxed), 1);
+    blocks.put_new(0, b"hello "[..].into());
+    assert_eq!(checks.load(Ordering::Relaxed), 1);
+    assert!(blocks.is_valid());
+
+    assert_eq!(blocks.verify(), Some(true));
+    assert_eq!(checks.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn test_on_complete_invalid() {
+    let blocks = Blocks::default();
+    blocks.put_new(0, b"hello world"[..].into());
+
+    // The blocks are already complete, so they are checked immediately.
+    blocks.on_complete(11, |body| body.concat() == b"goodbye");
+    assert!(!blocks.is_valid());
+    assert_eq!(blocks.verify(), Some(false));
+}
+
+#[tokio::test]
+async fn test_on_complete_blocking() {
+    let blocks = Blocks::default();
+    let (sender, receiver) = oneshot::channel();
+    let sender = Mutex::new(Some(sender));
+
+    blocks.on_complete(11, move |_| {
+        if let Some(sender) = sender.lock().take() {
+            let _ = sender.send(thread::current().id());
+        }
+        false
+    });
+
+    // Within a runtime, the check runs on a blocking thread.
+    blocks.put_new(0, b"hello world"[..].into());
+    assert_ne!(receiver.await.unwrap(), thread::current().id());
+
+    // The blocks are invalidated once the check returns.
+    eventually(|| !blocks.is_valid()).await;
+}
@@ -1,11 +1,38 @@
+use std::pin::Pin;
 use std::sync::{atomic::AtomicUsize, Arc};
 
 use crate::blocks::Blocks;
 use crate::body_reader::*;
+use crate::types::*;
 
-use super::{SimpleRequester, GOODBYE, HELLO_WORLD};
-use bytes::Bytes;
-use futures::{stream, StreamExt};
+use super::{SimpleRequester, SimpleResponse, GOODBYE, HELLO_WORLD};
+use bytes::{Bytes, BytesMut};
+use futures::{future, stream, Future, StreamExt};
+
+/// A requester which ignores the requested range and returns the whole body
+/// of [`HELLO_WORLD`] in two chunks.
+struct WholeBodyRequester;
+
+impl Requester<SimpleResponse> for WholeBodyRequester {
+    fn fetch(
+        &self,
+        _range: &RequestRange,
+    ) -> Pin<Box<dyn Future<Output = Result<RequesterStatus<SimpleResponse>>> + Send + Sync>> {
+        let (hello, world) = HELLO_WORLD.split_at(5);
+        let body = stream::iter([hello, world]).map(|v| Ok(Bytes::from(v)));
+        let range = ResponseRange {
+            bytes_len: HELLO_WORLD.len(),
+            bytes_range: RequestRange::None,
+        };
+
+        Box::pin(future::ready(Ok(RequesterStatus::Cache(
+            SimpleResponse(Box::pin(body)),
+            range,
+            None,
+            None,
+        ))))
+    }
+}
 
 #[test]
 fn test_block_body_reader() {
@@ -49,12 +76,33 @@ async fn test_stream_body_reader() {
     assert!(value.is_none());
 }
 
+#[tokio::test]
+async fn test_stream_body_reader_overflow() {
+    let values = stream::iter(vec![HELLO_WORLD, GOODBYE]).map(|v| Ok(Bytes::from(v)));
+
+    let mut reader = StreamBodyReader::new(Box::pin(values));
+    let mut offset = 0;
+    let end = 5;
+
+    let value = reader.next(&mut offset, end).await;
+    assert_eq!(value.unwrap().unwrap().as_ref(), b"hello");
+    assert_eq!(offset, end);
+    assert!(reader.overflowed());
+
+    let value = reader.next(&mut offset, end + 1).await;
+    assert!(matches!(value, Some(Err(Error::UpstreamProtocol(..)))));
+}
+
 #[tokio::test]
 async fn test_tee_body_reader() {
     let values = stream::iter(vec![HELLO_WORLD, GOODBYE]).map(|v| Ok(Bytes::from(v)));
     let blocks = Blocks::default();
 
-    let mut reader = TeeBodyReader::new(blocks.clone(), Box::pin(values));
+    let mut reader = TeeBodyReader::new(
+        blocks.clone(),
+        Box::pin(values),
+        0..HELLO_WORLD.len() + GOODBYE.len(),
+    );
     let mut offset = 0;
     let end = HELLO_WORLD.len() + GOODBYE.len();
 
@@ -82,6 +130,59 @@ async fn test_tee_body_reader() {
     );
 }
 
+#[tokio::test]
+async fn test_tee_body_reader_skip() {
+    let values = stream::iter(vec![HELLO_WORLD, GOODBYE]).map(|v| Ok(Bytes::from(v)));
+    let blocks = Blocks::default();
+
+    // The stream starts before the requested offset, and ends after the requested end.
+    let mut reader = TeeBodyReader::new(
+        blocks.clone(),
+        Box::pin(values),
+        0..HELLO_WORLD.len() + GOODBYE.len(),
+    );
+    let mut offset = 6;
+    let end = HELLO_WORLD.len() + 3;
+
+    let value = reader.next(&mut offset, end).await;
+    assert_eq!(value.unwrap().unwrap().as_ref(), b"world");
+    assert_eq!(offset, HELLO_WORLD.len());
+
+    let value = reader.next(&mut offset, end).await;
+    assert_eq!(value.unwrap().unwrap().as_ref(), b"goo");
+    assert_eq!(offset, end);
+
+    // All bytes read from the stream are stored.
+    assert_eq!(
+        blocks.get(0, HELLO_WORLD.len()).unwrap().as_ref(),
+        HELLO_WORLD
+    );
+    assert_eq!(
+        blocks
+            .get(HELLO_WORLD.len(), GOODBYE.len())
+            .unwrap()
+            .as_ref(),
+        GOODBYE
+    );
+}
+
+#[tokio::test]
+async fn test_tee_body_reader_overflow() {
+    let values = stream::iter(vec![HELLO_WORLD]).map(|v| Ok(Bytes::from(v)));
+    let blocks = Blocks::default();
+
+    // The stream is longer than its declared bounds, so it is truncated.
+    let mut reader = TeeBodyReader::new(blocks.clone(), Box::pin(values), 0..5);
+    let mut offset = 0;
+
+    let value = reader.next(&mut offset, 5).await;
+    assert_eq!(value.unwrap().unwrap().as_ref(), b"hello");
+    assert_eq!(offset, 5);
+
+    assert!(!blocks.is_valid());
+    assert!(blocks.get(5, 6).is_none());
+}
+
 #[tokio::test]
 async fn test_adaptive_body_reader() {
     let blocks = Blocks::default();
@@ -116,3 +217,136 @@ async fn test_adaptive_body_reader() {
         GOODBYE
     );
 }
+
+#[tokio::test]
+async fn test_adaptive_body_reader_passthrough_refill() {
+    let blocks = Blocks::default();
+    blocks.put_new(0, HELLO_WORLD.into());
+
+    let request_count = Arc::new(AtomicUsize::default());
+    let requester = Arc::new(SimpleRequester::new(request_count, false));
+    let mut reader = AdaptiveReader::new_adaptive(requester, blocks);
+    let mut offset = HELLO_WORLD.len();
+    let end = HELLO_WORLD.len() + GOODBYE.len();
+
+    let value = reader.next(&mut offset, end).await;
+    assert!(matches!(
+        value,
+        Some(Err(crate::types::Error::CacheInconsistency(..)))
+    ));
+    assert_eq!(offset, HELLO_WORLD.len());
+}
+
+#[tokio::test]
+async fn test_adaptive_body_reader_whole_body_refill() {
+    let blocks = Blocks::default();
+    let reader = AdaptiveReader::new_adaptive(Arc::new(WholeBodyRequester), blocks.clone());
+
+    let body = reader
+        .into_stream(3, 8)
+        .map(|x| x.unwrap())
+        .collect::<BytesMut>()
+        .await;
+    assert_eq!(body.as_ref(), &HELLO_WORLD[3..8]);
+
+    // The bytes outside of the requested range are stored too.
+    assert_eq!(blocks.get(0, 5).unwrap().as_ref(), &HELLO_WORLD[..5]);
+    assert_eq!(blocks.get(5, 6).unwrap().as_ref(), &HELLO_WORLD[5..]);
+}
+
+#[tokio::test]
+async fn test_adaptive_body_reader_short_body_refill() {
+    let blocks = Blocks::default();
+    let range = ResponseRange {
+        bytes_len: HELLO_WORLD.len(),
+        bytes_range: RequestRange::None,
+    };
+
+    // The initial body ends early, so the rest is fetched again.
+    let values = stream::iter([&HELLO_WORLD[..5]]).map(|v| Ok(Bytes::from(v)));
+    let reader = AdaptiveReader::new_from_body_stream(
+        Arc::new(WholeBodyRequester),
+        blocks.clone(),
+        Box::pin(values),
+        &range,
+    );
+
+    let body = reader
+        .into_stream(0, HELLO_WORLD.len())
+        .map(|x| x.unwrap())
+        .collect::<BytesMut>()
+        .await;
+    assert_eq!(body.as_ref(), HELLO_WORLD);
+    assert!(blocks.holes(0..HELLO_WORLD.len()).is_empty());
+}
+
+#[tokio::test]
+async fn test_adaptive_body_reader_reset_body_refill() {
+    let blocks = Blocks::default();
+    let range = ResponseRange {
+        bytes_len: HELLO_WORLD.len(),
+        bytes_range: RequestRange::None,
+    };
+
+    // The connection of the initial body is reset after its first chunk, so the rest is
+    // fetched again.
+    let values = stream::iter([
+        Ok(Bytes::from(&HELLO_WORLD[..5])),
+        Err(Error::UpstreamProtocol("connection reset".into())),
+    ]);
+    let reader = AdaptiveReader::new_from_body_stream(
+        Arc::new(WholeBodyRequester),
+        blocks.clone(),
+        Box::pin(values),
+        &range,
+    );
+
+    let body = reader
+        .into_stream(0, HELLO_WORLD.len())
+        .map(|x| x.unwrap())
+        .collect::<BytesMut>()
+        .await;
+    assert_eq!(body.as_ref(), HELLO_WORLD);
+    assert!(blocks.holes(0..HELLO_WORLD.len()).is_empty());
+}
+
+#[tokio::test]
+async fn test_adaptive_body_reader_short_refill() {
+    // The refill for the missing bytes ends without returning any of them.
+    let mut reader = AdaptiveReader::new_adaptive(Arc::new(WholeBodyRequester), Blocks::default());
+    let mut offset = 20;
+    let end = 30;
+
+    let value = reader.next(&mut offset, end).await;
+    assert!(matches!(value, Some(Err(Error::UpstreamProtocol(..)))));
+    assert!(reader.next(&mut offset, end).await.is_none());
+}
+
+#[tokio::test]
+async fn test_adaptive_body_reader_stalled_body_refill() {
+    let blocks = Blocks::default();
+    let range = ResponseRange {
+        bytes_len: HELLO_WORLD.len(),
+        bytes_range: RequestRange::None,
+    };
+
+    // The initial body stalls after its first chunk, so the rest is fetched again.
+    let values = stream::iter([
+        Ok(Bytes::from(&HELLO_WORLD[..5])),
+        Err(Error::UpstreamTimeout("stalled".into())),
+    ]);
+    let reader = AdaptiveReader::new_from_body_stream(
+        Arc::new(WholeBodyRequester),
+        blocks.clone(),
+        Box::pin(values),
+        &range,
+    );
+
+    let body = reader
+        .into_stream(0, HELLO_WORLD.len())
+        .map(|x| x.unwrap())
+        .collect::<BytesMut>()
+        .await;
+    assert_eq!(body.as_ref(), HELLO_WORLD);
+    assert!(blocks.holes(0..HELLO_WORLD.len()).is_empty());
+}
@@ -0,0 +1,94 @@
+use std::io::SeekFrom;
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::*;
+use crate::blocks::Blocks;
+use crate::object_reader::ObjectReader;
+use crate::Service;
+
+#[tokio::test]
+async fn test_read_and_seek_blocks() {
+    let blocks = Blocks::default();
+    blocks.put_new(0, HELLO_WORLD.into());
+
+    let request_count = Arc::new(AtomicUsize::default());
+    let requester = Arc::new(SimpleRequester::new(request_count.clone(), true));
+    let mut reader = ObjectReader::new(requester, blocks, HELLO_WORLD.len());
+
+    let mut buf = [0; 5];
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    assert_eq!(reader.seek(SeekFrom::End(-5)).await.unwrap(), 6);
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"world");
+
+    assert_eq!(reader.seek(SeekFrom::Current(-3)).await.unwrap(), 8);
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, b"rld");
+
+    assert!(reader.seek(SeekFrom::Current(-100)).await.is_err());
+    assert_eq!(request_count.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_read_fills_holes() {
+    let blocks = Blocks::default();
+    blocks.put_new(0, HELLO_WORLD.into());
+
+    let request_count = Arc::new(AtomicUsize::default());
+    let requester = Arc::new(SimpleRequester::new(request_count.clone(), true));
+    let end = HELLO_WORLD.len() + GOODBYE.len();
+    let mut reader = ObjectReader::new(requester, blocks.clone(), end);
+
+    reader.seek(SeekFrom::Start(6)).await.unwrap();
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, b"worldgoodbye");
+    assert_eq!(request_count.load(Ordering::Relaxed), 1);
+
+    // The hole is now filled, so reading it again does not make a request.
+    reader
+        .seek(SeekFrom::Start(HELLO_WORLD.len() as u64))
+        .await
+        .unwrap();
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, GOODBYE);
+    assert_eq!(request_count.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_service_open() {
+    let backend = Arc::new(SimpleRequestBackend::new(true));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    let mut reader = service.open(&0, &"/".into(), &None).await.unwrap().unwrap();
+    assert_eq!(reader.len(), GOODBYE.len());
+
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body).await.unwrap();
+    assert_eq!(body, GOODBYE);
+
+    let mut reader = service.open(&0, &"/".into(), &None).await.unwrap().unwrap();
+    reader.seek(SeekFrom::Start(4)).await.unwrap();
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body).await.unwrap();
+    assert_eq!(body, b"bye");
+    assert_eq!(backend.request_count(), 1);
+}
+
+#[tokio::test]
+async fn test_service_open_passthrough() {
+    let backend = Arc::new(SimpleRequestBackend::new(false));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    assert!(service
+        .open(&0, &"/".into(), &None)
+        .await
+        .unwrap()
+        .is_none());
+}
@@ -4,7 +4,8 @@ use std::sync::Arc;
 use bytes::BytesMut;
 use futures::StreamExt;
 
-use super::{SimpleRequester, GOODBYE};
+use super::{eventually, SimpleRequester, SimpleResponse, GOODBYE};
+use crate::blocks::Blocks;
 use crate::response_builder::ResponseBuilder;
 use crate::types::*;
 
@@ -17,7 +18,15 @@ async fn test_response_builder() {
     else {
         panic!()
     };
-    let (resp, builder) = ResponseBuilder::new(resp, &range, data, requester).unwrap();
+    let (resp, builder) = ResponseBuilder::new(
+        resp,
+        &range,
+        &RequestRange::None,
+        data,
+        requester,
+        Blocks::default(),
+    )
+    .unwrap();
 
     let stream = resp
         .into_body()
@@ -27,6 +36,9 @@ async fn test_response_builder() {
     assert_eq!(stream.as_ref(), GOODBYE);
     assert_eq!(request_count.load(Ordering::Relaxed), 1);
 
+    // The data is completed once the whole body has been stored and checked.
+    eventually(|| builder.data() == &Some(GOODBYE.len())).await;
+
     let stream = builder
         .stream(&RequestRange::FromTo(0, 0))
         .unwrap()
@@ -37,3 +49,125 @@ async fn test_response_builder() {
     assert_eq!(stream.as_ref(), &b""[..]);
     assert_eq!(request_count.load(Ordering::Relaxed), 1);
 }
+
+#[test]
+fn test_response_builder_complete_data() {
+    let request_count = Arc::new(AtomicUsize::default());
+    let requester = Arc::new(SimpleRequester::new(request_count, true));
+    let range = ResponseRange {
+        bytes_len: GOODBYE.len(),
+        bytes_range: RequestRange::None,
+    };
+    let blocks = Blocks::default();
+    let builder = ResponseBuilder::new_empty(&range, None, requester, blocks.clone());
+
+    // Only part of the body is stored, so the data is unchanged.
+    blocks.put_new(0, GOODBYE[..4].into());
+    assert_eq!(builder.data(), &None);
+
+    blocks.put_new(4, GOODBYE[4..].into());
+    assert_eq!(builder.data(), &Some(GOODBYE.len()));
+    assert_eq!(builder.clone().data(), &Some(GOODBYE.len()));
+}
+
+async fn collect_stream(
+    builder: &ResponseBuilder<SimpleResponse>,
+    range: RequestRange,
+) -> BytesMut {
+    builder
+        .stream(&range)
+        .unwrap()
+        .into_body()
+        .map(|x| x.unwrap())
+        .collect::<BytesMut>()
+        .await
+}
+
+#[tokio::test]
+async fn test_response_builder_ranges() {
+    let request_count = Arc::new(AtomicUsize::default());
+    let requester = Arc::new(SimpleRequester::new(request_count.clone(), true));
+    let range = RequestRange::default();
+    let RequesterStatus::Cache(resp, range, _, data) = requester.fetch(&range).await.unwrap()
+    else {
+        panic!()
+    };
+    let (resp, builder) = ResponseBuilder::new(
+        resp,
+        &range,
+        &RequestRange::None,
+        data,
+        requester,
+        Blocks::default(),
+    )
+    .unwrap();
+
+    // Consume the initial response so that later ranges are served from blocks.
+    let _ = resp.into_body().collect::<Vec<_>>().await;
+
+    let stream = collect_stream(&builder, RequestRange::Last(3)).await;
+    assert_eq!(stream.as_ref(), b"bye");
+
+    let stream = collect_stream(&builder, RequestRange::Last(100)).await;
+    assert_eq!(stream.as_ref(), GOODBYE);
+
+    let stream = collect_stream(&builder, RequestRange::FromTo(5, 100)).await;
+    assert_eq!(stream.as_ref(), b"ye");
+
+    let stream = collect_stream(&builder, RequestRange::AllFrom(GOODBYE.len() - 1)).await;
+    assert_eq!(stream.as_ref(), b"e");
+
+    for range in [
+        RequestRange::AllFrom(GOODBYE.len()),
+        RequestRange::FromTo(GOODBYE.len(), GOODBYE.len() + 1),
+        RequestRange::FromTo(5000, 6000),
+        RequestRange::Last(0),
+    ] {
+        assert!(matches!(
+            builder.stream(&range),
+            Err(Error::UnsatisfiableRange(len)) if len == GOODBYE.len()
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_response_builder_empty() {
+    let request_count = Arc::new(AtomicUsize::default());
+    let requester = Arc::new(SimpleRequester::new(request_count.clone(), true));
+    let range = ResponseRange {
+        bytes_len: 0,
+        bytes_range: RequestRange::None,
+    };
+    let resp = SimpleResponse(Box::pin(futures::stream::empty()));
+    let (resp, builder) = ResponseBuilder::new(
+        resp,
+        &range,
+        &RequestRange::None,
+        None,
+        requester,
+        Blocks::default(),
+    )
+    .unwrap();
+
+    let stream = resp
+        .into_body()
+        .map(|x| x.unwrap())
+        .collect::<BytesMut>()
+        .await;
+    assert!(stream.is_empty());
+
+    let stream = collect_stream(&builder, RequestRange::None).await;
+    assert!(stream.is_empty());
+
+    for range in [
+        RequestRange::AllFrom(0),
+        RequestRange::Last(1),
+        RequestRange::FromTo(0, 1),
+    ] {
+        assert!(matches!(
+            builder.stream(&range),
+            Err(Error::UnsatisfiableRange(0))
+        ));
+    }
+    assert_eq!(request_count.load(Ordering::Relaxed), 0);
+}
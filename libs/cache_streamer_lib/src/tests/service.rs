@@ -1,23 +1,96 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::pin::Pin;
 use std::sync::Arc;
 
+use bytes::{Bytes, BytesMut};
+use futures::{future, stream, Future, StreamExt};
+
 use super::*;
+use crate::storage::MmapArena;
 use crate::Service;
 
 fn test_path() -> String {
     "/".into()
 }
 
+/// A requester which returns [`HELLO_WORLD`] in two chunks without reporting its length,
+/// and counts its requests.
+struct UnknownLengthRequester {
+    count: Arc<AtomicUsize>,
+    max_len: usize,
+}
+
+impl Requester<SimpleResponse> for UnknownLengthRequester {
+    fn fetch(
+        &self,
+        _range: &RequestRange,
+    ) -> Pin<Box<dyn Future<Output = Result<RequesterStatus<SimpleResponse>>> + Send + Sync>> {
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        let (hello, world) = HELLO_WORLD.split_at(5);
+        let body = stream::iter([hello, world]).map(|v| Ok(Bytes::from(v)));
+
+        Box::pin(future::ready(Ok(RequesterStatus::CacheUnknownLength(
+            SimpleResponse(Box::pin(body)),
+            self.max_len,
+            Some(EXPIRE_TIME),
+            None,
+        ))))
+    }
+}
+
+struct UnknownLengthRequestBackend {
+    count: Arc<AtomicUsize>,
+    max_len: usize,
+}
+
+impl UnknownLengthRequestBackend {
+    fn new(max_len: usize) -> Self {
+        Self {
+            count: Arc::default(),
+            max_len,
+        }
+    }
+
+    fn request_count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl RequestBackend<String, SimpleResponse> for UnknownLengthRequestBackend {
+    fn create_for_key(
+        &self,
+        _key: &String,
+        _request: &Option<usize>,
+    ) -> Arc<dyn Requester<SimpleResponse>> {
+        Arc::new(UnknownLengthRequester {
+            count: self.count.clone(),
+            max_len: self.max_len,
+        })
+    }
+}
+
+async fn read_body(status: ServiceStatus<SimpleResponse>) -> BytesMut {
+    let (ServiceStatus::Cache(response, _) | ServiceStatus::Passthrough(response)) = status;
+
+    response
+        .into_body()
+        .map(|x| x.unwrap())
+        .collect::<BytesMut>()
+        .await
+}
+
 #[tokio::test]
 async fn test_cache() {
     let backend = Arc::new(SimpleRequestBackend::new(true));
     let service = Service::new(backend.clone(), 1_000_000);
 
     let _ = service
-        .call(&0, &test_path(), &RequestRange::None)
+        .call(&0, &test_path(), &RequestRange::None, &None)
         .await
         .unwrap();
     let _ = service
-        .call(&0, &test_path(), &RequestRange::None)
+        .call(&0, &test_path(), &RequestRange::None, &None)
         .await
         .unwrap();
     assert_eq!(backend.request_count(), 1);
@@ -29,11 +102,11 @@ async fn test_no_cache_on_passthrough() {
     let service = Service::new(backend.clone(), 1_000_000);
 
     let _ = service
-        .call(&0, &test_path(), &RequestRange::None)
+        .call(&0, &test_path(), &RequestRange::None, &None)
         .await
         .unwrap();
     let _ = service
-        .call(&0, &test_path(), &RequestRange::None)
+        .call(&0, &test_path(), &RequestRange::None, &None)
         .await
         .unwrap();
     assert_eq!(backend.request_count(), 2);
@@ -45,12 +118,310 @@ async fn test_expire() {
     let service = Service::new(backend.clone(), 1_000_000);
 
     let _ = service
-        .call(&0, &test_path(), &RequestRange::None)
+        .call(&0, &test_path(), &RequestRange::None, &None)
         .await
         .unwrap();
     let _ = service
-        .call(&(EXPIRE_TIME + 1), &test_path(), &RequestRange::None)
+        .call(&(EXPIRE_TIME + 1), &test_path(), &RequestRange::None, &None)
         .await
         .unwrap();
     assert_eq!(backend.request_count(), 2);
 }
+
+#[tokio::test]
+async fn test_call_metadata() {
+    let backend = Arc::new(SimpleRequestBackend::new(true));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    let _ = service
+        .call_metadata(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    let _ = service
+        .call_metadata(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert_eq!(backend.request_count(), 1);
+
+    // The body was not stored, so it is fetched when it is read.
+    let ServiceStatus::Cache(response, detail) = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap()
+    else {
+        panic!()
+    };
+    assert_eq!(detail.status, CacheStatus::PartialHit);
+    let body = response
+        .into_body()
+        .map(|x| x.unwrap())
+        .collect::<BytesMut>()
+        .await;
+    assert_eq!(body.as_ref(), GOODBYE);
+    assert_eq!(backend.request_count(), 2);
+}
+
+#[tokio::test]
+async fn test_call_metadata_passthrough() {
+    let backend = Arc::new(SimpleRequestBackend::new(false));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    let status = service
+        .call_metadata(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert!(matches!(status, ServiceStatus::Passthrough(..)));
+    assert_eq!(backend.request_count(), 1);
+}
+
+#[tokio::test]
+async fn test_storage_failure() {
+    // The arena cannot hold the body, so the response is passed through.
+    let backend = Arc::new(SimpleRequestBackend::new(true));
+    let arena = MmapArena::with_capacity(GOODBYE.len() - 1).unwrap();
+    let service = Service::with_storage(backend.clone(), Arc::new(arena), 1_000_000);
+
+    let status = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert!(matches!(status, ServiceStatus::Passthrough(..)));
+    assert_eq!(read_body(status).await.as_ref(), GOODBYE);
+
+    let status = service
+        .call_metadata(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert!(matches!(status, ServiceStatus::Passthrough(..)));
+    assert_eq!(backend.request_count(), 2);
+}
+
+#[tokio::test]
+async fn test_cache_status() {
+    let backend = Arc::new(SimpleRequestBackend::new(true));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    let ServiceStatus::Cache(response, detail) = service
+        .call(&1, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap()
+    else {
+        panic!()
+    };
+    assert_eq!(detail.status, CacheStatus::Miss);
+    assert_eq!(detail.insertion_time, 1);
+    let _ = response.into_body().collect::<Vec<_>>().await;
+
+    let ServiceStatus::Cache(_, detail) = service
+        .call(&2, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap()
+    else {
+        panic!()
+    };
+    assert_eq!(detail.status, CacheStatus::Hit);
+    assert_eq!(detail.insertion_time, 1);
+}
+
+#[tokio::test]
+async fn test_variants() {
+    let backend = Arc::new(SimpleRequestBackend::new(true));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    for variant in [1, 2, 1, 2] {
+        let _ = service
+            .call(&0, &test_path(), &RequestRange::None, &Some(variant))
+            .await
+            .unwrap();
+    }
+    assert_eq!(backend.request_count(), 2);
+}
+
+#[tokio::test]
+async fn test_max_variants() {
+    let backend = Arc::new(SimpleRequestBackend::new(true));
+    let service = Service::new(backend.clone(), 1_000_000).with_max_variants(1);
+
+    for variant in [1, 2, 1] {
+        let _ = service
+            .call(&0, &test_path(), &RequestRange::None, &Some(variant))
+            .await
+            .unwrap();
+    }
+    assert_eq!(backend.request_count(), 3);
+}
+
+#[tokio::test]
+async fn test_max_variants_zero() {
+    // At least one variant is always stored.
+    let backend = Arc::new(SimpleRequestBackend::new(true));
+    let service = Service::new(backend.clone(), 1_000_000).with_max_variants(0);
+
+    for variant in [1, 1] {
+        let _ = service
+            .call(&0, &test_path(), &RequestRange::None, &Some(variant))
+            .await
+            .unwrap();
+    }
+    assert_eq!(backend.request_count(), 1);
+}
+
+#[tokio::test]
+async fn test_variants_replaced() {
+    let backend = Arc::new(SimpleRequestBackend::new(true));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    // A response which does not vary replaces the stored variants.
+    for variant in [Some(1), None, Some(1), None] {
+        let _ = service
+            .call(&0, &test_path(), &RequestRange::None, &variant)
+            .await
+            .unwrap();
+    }
+    assert_eq!(backend.request_count(), 2);
+}
+
+#[tokio::test]
+async fn test_unknown_length() {
+    let backend = Arc::new(UnknownLengthRequestBackend::new(1_000));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    // The response is passed through, and only cached once its body has been read.
+    let status = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert!(matches!(status, ServiceStatus::Passthrough(..)));
+    assert_eq!(read_body(status).await.as_ref(), HELLO_WORLD);
+
+    let status = service
+        .call(&1, &test_path(), &RequestRange::FromTo(6, 11), &None)
+        .await
+        .unwrap();
+    let ServiceStatus::Cache(_, detail) = &status else {
+        panic!()
+    };
+    assert_eq!(detail.status, CacheStatus::Hit);
+    assert_eq!(detail.insertion_time, 0);
+    assert_eq!(read_body(status).await.as_ref(), b"world");
+    assert_eq!(backend.request_count(), 1);
+}
+
+#[tokio::test]
+async fn test_unknown_length_incomplete() {
+    let backend = Arc::new(UnknownLengthRequestBackend::new(1_000));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    // The body is dropped before it ends, so nothing is cached.
+    let status = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    let ServiceStatus::Passthrough(response) = status else {
+        panic!()
+    };
+    let _ = response.into_body().next().await;
+
+    let status = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert!(matches!(status, ServiceStatus::Passthrough(..)));
+    assert_eq!(backend.request_count(), 2);
+}
+
+#[tokio::test]
+async fn test_unknown_length_concurrent() {
+    let backend = Arc::new(UnknownLengthRequestBackend::new(1_000));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    let first = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    let second = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+
+    // Only the first request stores the body, so reading the second caches nothing.
+    assert_eq!(read_body(second).await.as_ref(), HELLO_WORLD);
+    let status = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert!(matches!(status, ServiceStatus::Passthrough(..)));
+    assert_eq!(backend.request_count(), 3);
+
+    assert_eq!(read_body(first).await.as_ref(), HELLO_WORLD);
+    let status = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert!(matches!(status, ServiceStatus::Cache(..)));
+    assert_eq!(read_body(status).await.as_ref(), HELLO_WORLD);
+    assert_eq!(backend.request_count(), 3);
+}
+
+#[tokio::test]
+async fn test_unknown_length_too_large() {
+    let backend = Arc::new(UnknownLengthRequestBackend::new(HELLO_WORLD.len() - 1));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    for _ in 0..2 {
+        let status = service
+            .call(&0, &test_path(), &RequestRange::None, &None)
+            .await
+            .unwrap();
+        assert!(matches!(status, ServiceStatus::Passthrough(..)));
+        assert_eq!(read_body(status).await.as_ref(), HELLO_WORLD);
+    }
+    assert_eq!(backend.request_count(), 2);
+}
+
+#[tokio::test]
+async fn test_unknown_length_storage() {
+    // Storage is created for the length of the body rather than the maximum length.
+    let backend = Arc::new(UnknownLengthRequestBackend::new(1_000));
+    let arena = MmapArena::with_capacity(HELLO_WORLD.len()).unwrap();
+    let service = Service::with_storage(backend.clone(), Arc::new(arena), 1_000_000);
+
+    let status = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert_eq!(read_body(status).await.as_ref(), HELLO_WORLD);
+
+    let status = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert!(matches!(status, ServiceStatus::Cache(..)));
+    assert_eq!(read_body(status).await.as_ref(), HELLO_WORLD);
+    assert_eq!(backend.request_count(), 1);
+}
+
+#[tokio::test]
+async fn test_scrub() {
+    let backend = Arc::new(SimpleRequestBackend::new(true));
+    let service = Service::new(backend.clone(), 1_000_000);
+
+    let status = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap();
+    assert_eq!(read_body(status).await.as_ref(), GOODBYE);
+
+    // The stored body is intact, so nothing is evicted.
+    assert_eq!(service.scrub(), 0);
+
+    let ServiceStatus::Cache(_, detail) = service
+        .call(&0, &test_path(), &RequestRange::None, &None)
+        .await
+        .unwrap()
+    else {
+        panic!()
+    };
+    assert_eq!(detail.status, CacheStatus::Hit);
+    assert_eq!(backend.request_count(), 1);
+}
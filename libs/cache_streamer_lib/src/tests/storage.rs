@@ -0,0 +1,57 @@
+use bytes::Bytes;
+
+use crate::storage::*;
+use crate::types::*;
+
+fn test_storage(backend: &dyn StorageBackend) {
+    let storage = backend.create_for_len(16).unwrap();
+    assert_eq!(storage.holes(0..16), vec![0..16]);
+    assert_eq!(storage.get(0, 5), None);
+
+    storage.put(0, Bytes::from_static(b"hello world"));
+    storage.put(6, Bytes::from_static(b"earth"));
+    assert_eq!(storage.get(0, 5).unwrap().as_ref(), b"hello");
+    assert_eq!(storage.get(6, 16).unwrap().as_ref(), b"world");
+    assert_eq!(storage.holes(0..16), vec![11..16]);
+
+    assert_eq!(storage.evict(3..8), 5);
+    assert_eq!(storage.get(0, 16).unwrap().as_ref(), b"hel");
+    assert_eq!(storage.get(3, 16), None);
+    assert_eq!(storage.get(8, 16).unwrap().as_ref(), b"rld");
+    assert_eq!(storage.holes(0..16), vec![3..8, 11..16]);
+}
+
+#[test]
+fn test_memory_storage() {
+    test_storage(&MemoryStorageBackend);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_file_storage() {
+    test_storage(&FileStorageBackend::new(std::env::temp_dir()));
+}
+
+#[test]
+fn test_mmap_storage() {
+    test_storage(&MmapArena::with_capacity(16).unwrap());
+}
+
+#[test]
+fn test_mmap_arena_reuse() {
+    let arena = MmapArena::with_capacity(16).unwrap();
+
+    let first = arena.create_for_len(8).unwrap();
+    let second = arena.create_for_len(8).unwrap();
+    assert!(arena.create_for_len(1).is_err());
+
+    first.put(0, Bytes::from_static(b"aaaaaaaa"));
+    second.put(0, Bytes::from_static(b"bbbbbbbbbb"));
+    assert_eq!(first.get(0, 16).unwrap().as_ref(), b"aaaaaaaa");
+    assert_eq!(second.get(0, 16).unwrap().as_ref(), b"bbbbbbbb");
+
+    drop(first);
+    let third = arena.create_for_len(8).unwrap();
+    assert_eq!(third.get(0, 16), None);
+    assert_eq!(second.get(0, 16).unwrap().as_ref(), b"bbbbbbbb");
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use super::{SimpleRequester, SimpleResponse, GOODBYE};
+use crate::blocks::Blocks;
+use crate::response_builder::ResponseBuilder;
+use crate::types::*;
+use crate::variant_cache::VariantCache;
+
+/// Create a builder whose body is stored into `blocks`.
+fn builder(blocks: &Blocks) -> ResponseBuilder<SimpleResponse> {
+    let requester = Arc::new(SimpleRequester::new(Arc::default(), true));
+    let range = ResponseRange {
+        bytes_len: GOODBYE.len(),
+        bytes_range: RequestRange::None,
+    };
+
+    ResponseBuilder::new_empty(&range, None, requester, blocks.clone())
+}
+
+#[test]
+fn test_variants() {
+    let cache = VariantCache::new(GOODBYE.len() * 2, 2);
+    let key = String::from("a");
+
+    for variant in 0..3 {
+        let builder = builder(&Blocks::default());
+        cache.insert(&0, &key, &Some(variant), GOODBYE.len(), None, builder);
+    }
+
+    // Only the two most recent variants are stored, along with their marker.
+    assert!(cache.lookup(&0, &key, &Some(0)).is_none());
+    assert!(cache.lookup(&0, &key, &Some(1)).is_some());
+    assert!(cache.lookup(&0, &key, &Some(2)).is_some());
+    assert_eq!(cache.len(), 3);
+
+    // The cache is shrunk before each insertion, and the least recently used variant is
+    // evicted before the marker.
+    for other in ["b", "c"] {
+        let builder = builder(&Blocks::default());
+        cache.insert(&0, &other.into(), &None, GOODBYE.len(), None, builder);
+    }
+
+    assert!(cache.lookup(&0, &key, &Some(1)).is_none());
+    assert!(cache.lookup(&0, &key, &Some(2)).is_some());
+    assert_eq!(cache.len(), 4);
+}
+
+#[test]
+fn test_scrub() {
+    let cache = VariantCache::new(GOODBYE.len() * 4, 4);
+    let (a, b) = (String::from("a"), String::from("b"));
+    let blocks = [(); 3].map(|_| Blocks::default());
+
+    cache.insert(&0, &a, &Some(0), GOODBYE.len(), None, builder(&blocks[0]));
+    cache.insert(&0, &a, &Some(1), GOODBYE.len(), None, builder(&blocks[1]));
+    cache.insert(&0, &b, &Some(0), GOODBYE.len(), None, builder(&blocks[2]));
+    assert_eq!(cache.len(), 5);
+
+    // The marker is kept while any of its variants remain.
+    blocks[0].invalidate();
+    assert_eq!(cache.scrub(), 1);
+    assert_eq!(cache.len(), 4);
+    assert!(cache.lookup(&0, &a, &Some(1)).is_some());
+
+    // Markers are removed along with their last variant.
+    blocks[1].invalidate();
+    blocks[2].invalidate();
+    assert_eq!(cache.scrub(), 2);
+    assert_eq!(cache.len(), 0);
+}
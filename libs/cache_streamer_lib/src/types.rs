@@ -1,3 +1,4 @@
+use core::ops::Range;
 use std::pin::Pin;
 use std::sync::Arc;
 
@@ -33,22 +34,114 @@ pub struct ResponseRange {
     pub bytes_range: RequestRange,
 }
 
+impl ResponseRange {
+    /// The offsets of the bytes being returned by this request, within the file.
+    pub fn bounds(&self) -> Range<usize> {
+        let len = self.bytes_len;
+
+        match self.bytes_range {
+            RequestRange::None => 0..len,
+            RequestRange::AllFrom(start) => start..len,
+            RequestRange::Last(count) => len - count.min(len)..len,
+            RequestRange::FromTo(start, end) => start..end,
+        }
+    }
+}
+
+/// The type of boxed errors which are wrapped by [`Error`].
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The type of errors to be returned by this cache.
+#[derive(Debug)]
+pub enum Error {
+    /// Connecting to the upstream server failed, such as from a failed DNS lookup
+    /// or a refused connection.
+    UpstreamConnect(BoxError),
+
+    /// The upstream server did not respond in time.
+    UpstreamTimeout(BoxError),
+
+    /// The upstream server returned a malformed or unexpected response.
+    UpstreamProtocol(BoxError),
+
+    /// The requested range could not be converted or is not valid for the response.
+    InvalidRange,
+
+    /// The requested range does not overlap the response, which has the given
+    /// total number of bytes.
+    UnsatisfiableRange(usize),
+
+    /// The upstream server returned a response which does not match what was
+    /// previously cached.
+    CacheInconsistency(BoxError),
+
+    /// Creating storage for a response failed.
+    Storage(BoxError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UpstreamConnect(e) => write!(f, "failed to connect to upstream: {e}"),
+            Self::UpstreamTimeout(e) => write!(f, "upstream timed out: {e}"),
+            Self::UpstreamProtocol(e) => write!(f, "invalid upstream response: {e}"),
+            Self::InvalidRange => f.write_str("invalid range"),
+            Self::UnsatisfiableRange(len) => write!(f, "range not satisfiable for length {len}"),
+            Self::CacheInconsistency(e) => write!(f, "cache inconsistency: {e}"),
+            Self::Storage(e) => write!(f, "storage error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::UpstreamConnect(e)
+            | Self::UpstreamTimeout(e)
+            | Self::UpstreamProtocol(e)
+            | Self::CacheInconsistency(e)
+            | Self::Storage(e) => Some(e.as_ref()),
+            Self::InvalidRange | Self::UnsatisfiableRange(..) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Storage(value.into())
+    }
+}
+
 /// The type of results to be returned by this cache.
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+pub type Result<T> = std::result::Result<T, Error>;
 
 /// The type of body streams to be returned by this cache.
 pub type BodyStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send + Sync>>;
 
 /// The type of responses to be returned by this cache, and by upstream servers.
 pub trait Response: 'static {
-    /// The type of cache expiration times.
-    type Timepoint: Ord;
+    /// The type of cache insertion and expiration times.
+    type Timepoint: Ord + Clone + Send + Sync;
 
     /// Arbitrary data to store alongside a generic response.
     ///
     /// For HTTP, this could be used to store headers.
     /// If not needed, it can be set to `()`.
-    type Data: Clone;
+    type Data: Clone + Send + Sync;
+
+    /// Arbitrary data from the downstream request, passed to the [`RequestBackend`]
+    /// when creating a [`Requester`].
+    ///
+    /// For HTTP, this could be used to forward request headers.
+    /// If not needed, it can be set to `()`.
+    type RequestData: Clone + Send + Sync;
+
+    /// The type of keys which distinguish variants of a response, for responses which
+    /// differ based on the request.
+    ///
+    /// For HTTP, this could be the values of the request headers named by `vary`.
+    /// If not needed, it can be set to `()`.
+    type VariantKey: Ord + Clone + Send + Sync + 'static;
 
     /// Construct a new response from its constituent parts.
     fn from_parts(data: Self::Data, range: ResponseRange, body: BodyStream) -> Result<Self>
@@ -57,6 +150,47 @@ pub trait Response: 'static {
 
     /// Consume the response into its streaming body.
     fn into_body(self) -> BodyStream;
+
+    /// Transform the streaming body of the response, keeping everything else.
+    fn map_body<F>(self, f: F) -> Self
+    where
+        F: FnOnce(BodyStream) -> BodyStream,
+        Self: Sized;
+
+    /// Whether a cached response with the given data can be served for ranges of its
+    /// body. If not, the entire body is served regardless of the requested range.
+    ///
+    /// For HTTP, error responses are always served whole.
+    fn accepts_ranges(_data: &Self::Data) -> bool {
+        true
+    }
+
+    /// The key of the variant of a cached response with the given data which would be
+    /// returned for `request`, or [`None`] if the response does not vary.
+    ///
+    /// Variant keys are compared between responses with different data, so they should
+    /// identify what the response varies on as well as the request.
+    fn variant_key(_data: &Self::Data, _request: &Self::RequestData) -> Option<Self::VariantKey> {
+        None
+    }
+
+    /// Whether the complete body of a cached response with the given data is intact, such
+    /// as by comparing it against a checksum in the data. Responses whose body is not
+    /// intact are evicted.
+    ///
+    /// This is called once the whole body has been stored, on a blocking thread when
+    /// within a runtime, and again whenever the cache is scrubbed; see
+    /// [`Service::scrub`](crate::Service::scrub).
+    fn verify_body(_data: &Self::Data, _body: &[Bytes]) -> bool {
+        true
+    }
+
+    /// The data to replace the data of a cached response with once its whole body has
+    /// been stored and verified, such as to add a validator derived from the body. Returns
+    /// [`None`] to keep the data unchanged.
+    fn complete_data(_data: &Self::Data, _body: &[Bytes]) -> Option<Self::Data> {
+        None
+    }
 }
 
 /// Response variant for [`Requester`], indicating the cacheability of the response
@@ -72,15 +206,47 @@ pub enum RequesterStatus<R: Response> {
     ///    * The response returned the same range as the request
     Cache(R, ResponseRange, Option<R::Timepoint>, R::Data),
 
+    /// Cache this response once its body has been read, as its length is not known in
+    /// advance, with the given maximum length, cache expire time, and associated cache
+    /// data.
+    ///
+    /// The body is passed through as it is read, and is only cached if it ends cleanly
+    /// without exceeding the maximum length. This should only be returned for requests
+    /// for the entire body.
+    CacheUnknownLength(R, usize, Option<R::Timepoint>, R::Data),
+
     /// Passthrough this response.
     Passthrough(R),
 }
 
+/// How much of a response served from cache was already stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// The requested bytes were all stored.
+    Hit,
+
+    /// The response was stored, but some of the requested bytes must be fetched.
+    PartialHit,
+
+    /// The response was not stored, and was fetched and stored by this request.
+    Miss,
+}
+
+/// Details about a response served from cache.
+#[derive(Clone, Debug)]
+pub struct CacheDetail<T> {
+    /// How much of the response was already stored.
+    pub status: CacheStatus,
+
+    /// The time at which the response was inserted into the cache.
+    pub insertion_time: T,
+}
+
 /// Response variant for services, indicating whether the response from the service
 /// was served from cache or passed through.
 pub enum ServiceStatus<R: Response> {
     /// The response was served from cache.
-    Cache(R),
+    Cache(R, CacheDetail<R::Timepoint>),
 
     /// The response was passed through.
     Passthrough(R),
@@ -93,11 +259,56 @@ pub trait Requester<R: Response>: Send + Sync + 'static {
         &self,
         range: &RequestRange,
     ) -> Pin<Box<dyn Future<Output = Result<RequesterStatus<R>>> + Send + Sync>>;
+
+    /// Fetch a new copy of the response for the entire range, when only its metadata
+    /// is needed. The body of the returned response will not be read.
+    ///
+    /// The default implementation fetches the response with [`Requester::fetch`], so
+    /// requesters which can avoid transferring the body should override this.
+    fn fetch_metadata(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<RequesterStatus<R>>> + Send + Sync>> {
+        self.fetch(&RequestRange::None)
+    }
 }
 
 /// The type of a factory for requesters. Given a key, it will create
 /// a new requester specific to the key.
 pub trait RequestBackend<K, R: Response>: Send + Sync + 'static {
     /// Create a new [`Requester`] that fetches requests for this key.
-    fn create_for_key(&self, key: &K) -> Arc<dyn Requester<R>>;
+    ///
+    /// `request` is the data of the downstream request which caused the requester to be
+    /// created. If the response is cached, the requester will later be reused to fetch
+    /// missing bytes on behalf of other requests for the same key.
+    fn create_for_key(&self, key: &K, request: &R::RequestData) -> Arc<dyn Requester<R>>;
+}
+
+/// The type of storage for the body of a single cached response.
+///
+/// Storage is shared between all readers of a response, so implementations
+/// must synchronize internally.
+pub trait Storage: Send + Sync + 'static {
+    /// Gets the largest slice (smaller than `max_size`) stored at `offset`, or
+    /// [`None`] if nothing is stored at `offset`.
+    fn get(&self, offset: usize, max_size: usize) -> Option<Bytes>;
+
+    /// Stores `data` at the given offset, discarding sections which correspond to
+    /// offsets that are already stored.
+    ///
+    /// If the data cannot be stored, the affected offsets must remain holes, so that
+    /// they will be fetched again when requested.
+    fn put(&self, offset: usize, data: Bytes);
+
+    /// Returns the ranges within `range` which are not stored, in increasing order.
+    fn holes(&self, range: Range<usize>) -> Vec<Range<usize>>;
+
+    /// Discards all stored bytes within `range`, returning the number of bytes discarded.
+    fn evict(&self, range: Range<usize>) -> usize;
+}
+
+/// The type of a factory for storage. Given the length of a response body, it
+/// will create new storage for the body.
+pub trait StorageBackend: Send + Sync + 'static {
+    /// Create new [`Storage`] able to hold a body of `len` bytes.
+    fn create_for_len(&self, len: usize) -> Result<Arc<dyn Storage>>;
 }
@@ -0,0 +1,218 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use parking_lot::Mutex;
+use std::collections::{BTreeSet, VecDeque};
+
+use crate::response_builder::ResponseBuilder;
+use crate::types::*;
+use sized_ttl_cache::{Entry, SizedTTLCache};
+
+/// The cache of response builders, which stores variants of responses which vary
+/// based on the request.
+///
+/// The marker of a response which varies is always used more recently than its variants,
+/// so that the least recently used variants are evicted before the marker, and variants
+/// are never left without a marker.
+pub struct VariantCache<K, R>
+where
+    K: Ord + 'static,
+    R: Response,
+{
+    cache: Mutex<Cache<K, R>>,
+    max_variants: AtomicUsize,
+}
+
+impl<K, R> VariantCache<K, R>
+where
+    K: Ord + Clone + 'static,
+    R: Response,
+{
+    /// Create a new [`VariantCache`] with the given capacity, which stores at most
+    /// `max_variants` variants for each key.
+    ///
+    /// At least one variant is always stored.
+    pub fn new(capacity: usize, max_variants: usize) -> Self {
+        Self {
+            cache: Mutex::new(SizedTTLCache::with_capacity(capacity)),
+            max_variants: AtomicUsize::new(max_variants.max(1)),
+        }
+    }
+
+    /// Set the maximum number of variants stored for each key, which is at least one.
+    pub fn set_max_variants(&self, max_variants: usize) {
+        self.max_variants
+            .store(max_variants.max(1), Ordering::Relaxed);
+    }
+
+    /// Get the builder of the non-expired variant of an item which matches `request`,
+    /// along with the time it was inserted.
+    ///
+    /// Items whose stored body has been invalidated are removed instead.
+    pub fn lookup(
+        &self,
+        time: &R::Timepoint,
+        key: &K,
+        request: &R::RequestData,
+    ) -> Option<(R::Timepoint, ResponseBuilder<R>)> {
+        let mut cache = self.cache.lock();
+        let marker_key = (key.clone(), None);
+
+        let variant = match cache.get_with_insertion_time(time, &marker_key)? {
+            (_, CacheItem::Variants(data, _)) => Some(R::variant_key(data, request)?),
+            (_, CacheItem::Response(..)) => None,
+        };
+
+        let entry_key = (key.clone(), variant);
+        let (insertion_time, item) = match cache.get_with_insertion_time(time, &entry_key)? {
+            (insertion_time, CacheItem::Response(item)) => (insertion_time, item.clone()),
+            (_, CacheItem::Variants(..)) => return None,
+        };
+
+        if !item.is_valid() {
+            cache.remove(&entry_key);
+            return None;
+        }
+
+        if entry_key.1.is_some() {
+            cache.get(time, &marker_key);
+        }
+
+        Some((insertion_time, item))
+    }
+
+    /// Insert the builder of an item which was fetched for `request` into the cache,
+    /// with the given size and expiration time.
+    ///
+    /// If the item varies, it is stored as a variant, and the variant marker for the key
+    /// is updated. Otherwise, it replaces any variants stored for the key.
+    pub fn insert(
+        &self,
+        time: &R::Timepoint,
+        key: &K,
+        request: &R::RequestData,
+        size: usize,
+        expire_time: Option<R::Timepoint>,
+        item: ResponseBuilder<R>,
+    ) {
+        let variant = R::variant_key(item.data(), request);
+        let marker_key = (key.clone(), None);
+
+        let mut cache = self.cache.lock();
+
+        // Check whether the stored marker, if any, derives the same variant key, in which
+        // case the new variant can be added to it.
+        let (updated, removed) = match (cache.get(time, &marker_key), &variant) {
+            (Some(CacheItem::Variants(data, variants)), Some(new))
+                if R::variant_key(data, request).as_ref() == Some(new) =>
+            {
+                if !variants.contains(new) {
+                    variants.push_back(new.clone());
+                }
+
+                let evicted = match variants.len() > self.max_variants.load(Ordering::Relaxed) {
+                    true => variants.pop_front(),
+                    false => None,
+                };
+
+                (true, Vec::from_iter(evicted))
+            }
+            (Some(CacheItem::Variants(..)), _) => match cache.remove(&marker_key) {
+                Some(CacheItem::Variants(_, variants)) => (false, Vec::from(variants)),
+                _ => (false, Vec::new()),
+            },
+            (Some(CacheItem::Response(..)), Some(_)) => {
+                cache.remove(&marker_key);
+                (false, Vec::new())
+            }
+            _ => (false, Vec::new()),
+        };
+
+        for variant in removed {
+            cache.remove(&(key.clone(), Some(variant)));
+        }
+
+        if let (false, Some(variant)) = (updated, &variant) {
+            let variants = VecDeque::from([variant.clone()]);
+            let marker = CacheItem::Variants(item.data().clone(), variants);
+            cache.get_or_insert(time, &marker_key, Entry::from_parts(0, None, marker));
+        }
+
+        let is_variant = variant.is_some();
+        let entry = Entry::from_parts(size, expire_time, CacheItem::Response(item));
+        cache.get_or_insert(time, &(key.clone(), variant), entry);
+
+        if is_variant {
+            cache.get(time, &marker_key);
+        }
+    }
+
+    /// Verify the stored bodies of all items, and remove items whose body is invalid,
+    /// along with the markers of responses which have no variants left. Returns the
+    /// number of removed items, not counting markers.
+    ///
+    /// The cache is not locked while bodies are verified.
+    pub fn scrub(&self) -> usize {
+        let items = Vec::from_iter(self.cache.lock().values().filter_map(|item| match item {
+            CacheItem::Response(item) => Some(item.clone()),
+            CacheItem::Variants(..) => None,
+        }));
+
+        for item in items {
+            item.verify();
+        }
+
+        let mut cache = self.cache.lock();
+        let mut removed = 0;
+        let mut varying = BTreeSet::new();
+
+        cache.retain(|(key, variant), item| match item {
+            CacheItem::Response(item) if !item.is_valid() => {
+                removed += 1;
+                false
+            }
+            CacheItem::Response(..) => {
+                if variant.is_some() {
+                    varying.insert(key.clone());
+                }
+                true
+            }
+            CacheItem::Variants(..) => true,
+        });
+
+        cache.retain(|(key, _), item| match item {
+            CacheItem::Variants(..) => varying.contains(key),
+            CacheItem::Response(..) => true,
+        });
+
+        removed
+    }
+}
+
+#[cfg(test)]
+impl<K, R> VariantCache<K, R>
+where
+    K: Ord + 'static,
+    R: Response,
+{
+    /// Get the number of items in the cache, including variant markers.
+    pub fn len(&self) -> usize {
+        self.cache.lock().values().count()
+    }
+}
+
+/// The cache of items, keyed by the request key, and the variant key if the item
+/// varies based on the request.
+type Cache<K, R> = SizedTTLCache<
+    (K, Option<<R as Response>::VariantKey>),
+    <R as Response>::Timepoint,
+    CacheItem<R>,
+>;
+
+/// An item stored in the cache.
+enum CacheItem<R: Response> {
+    /// A response which does not vary, or one variant of a response.
+    Response(ResponseBuilder<R>),
+
+    /// A marker for a response which varies, stored without a variant key. Holds the data
+    /// which variant keys are derived from, and the stored variant keys, oldest first.
+    Variants(R::Data, VecDeque<R::VariantKey>),
+}
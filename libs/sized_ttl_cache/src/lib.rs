@@ -4,6 +4,7 @@ use intrusive_lru_cache::LRUCache;
 #[derive(Clone)]
 pub struct Entry<T, V> {
     size_bytes: usize,
+    insertion_time: Option<T>,
     expiration_time: Option<T>,
     inner: V,
 }
@@ -16,6 +17,7 @@ where
     pub fn from_parts(size_bytes: usize, expiration_time: Option<T>, inner: V) -> Self {
         Self {
             size_bytes,
+            insertion_time: None,
             expiration_time,
             inner,
         }
@@ -68,12 +70,39 @@ where
         }
     }
 
+    /// Gets the non-expired value corresponding to a key along with the time it was
+    /// inserted, or [`None`] if no value is available for the key.
+    pub fn get_with_insertion_time<'a, Q>(&'a mut self, time: &T, key: &Q) -> Option<(T, &'a mut V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        T: Clone,
+    {
+        let entry = self.cache.smart_get(key)?;
+
+        if entry.is_expired(time) {
+            let _ = entry.remove();
+            None
+        } else {
+            let entry = entry.into_mut().1;
+            let insertion_time = entry.insertion_time.clone().unwrap_or_else(|| time.clone());
+
+            Some((insertion_time, &mut entry.inner))
+        }
+    }
+
     /// Gets the non-expired value corresponding to a key, or inserts the given
-    /// data as the new value.
-    pub fn get_or_insert<'a, Q>(&'a mut self, time: &T, key: &Q, value: Entry<T, V>) -> &'a mut V
+    /// data as the new value with `time` as its insertion time.
+    pub fn get_or_insert<'a, Q>(
+        &'a mut self,
+        time: &T,
+        key: &Q,
+        mut value: Entry<T, V>,
+    ) -> &'a mut V
     where
         K: Borrow<Q>,
         Q: ToOwned<Owned = K> + Ord + ?Sized,
+        T: Clone,
     {
         self.shrink();
         self.get(time, key);
@@ -82,11 +111,48 @@ where
             .cache
             .get_or_insert2(key, || {
                 self.size_bytes += value.size_bytes;
+                value.insertion_time = Some(time.clone());
                 value
             })
             .inner
     }
 
+    /// Removes the value corresponding to a key, returning it if it was present.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let entry = self.cache.remove(key)?;
+        self.size_bytes -= entry.size_bytes;
+
+        Some(entry.inner)
+    }
+
+    /// Returns an iterator over all values, including expired values, without changing
+    /// the LRU order.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.cache.iter_peek_lru().map(|(_, entry)| &entry.inner)
+    }
+
+    /// Removes all values for which `predicate` returns `false`, without changing the
+    /// LRU order of the remaining values.
+    pub fn retain<F>(&mut self, mut predicate: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let size_bytes = &mut self.size_bytes;
+
+        self.cache.retain(|key, entry| {
+            let keep = predicate(key, &entry.inner);
+            if !keep {
+                *size_bytes -= entry.size_bytes;
+            }
+
+            keep
+        });
+    }
+
     fn shrink(&mut self) {
         while self.size_bytes > self.capacity_bytes {
             match self.cache.pop() {
@@ -119,4 +185,42 @@ mod tests {
         assert_eq!(cache.get(&0, "0"), None);
         assert_eq!(cache.get(&0, "1"), Some(&mut 1));
     }
+
+    #[test]
+    fn test_remove() {
+        let mut cache = SizedTTLCache::<String, usize, usize>::with_capacity(1);
+        cache.get_or_insert(&0, "0", Entry::from_parts(1, None, 0));
+
+        assert_eq!(cache.remove("0"), Some(0));
+        assert_eq!(cache.remove("0"), None);
+
+        // The removed size no longer counts towards the capacity.
+        cache.get_or_insert(&0, "1", Entry::from_parts(1, None, 1));
+        cache.get_or_insert(&0, "2", Entry::from_parts(0, None, 2));
+        assert_eq!(cache.get(&0, "1"), Some(&mut 1));
+    }
+
+    #[test]
+    fn test_insertion_time() {
+        let mut cache = SizedTTLCache::<String, usize, usize>::with_capacity(2);
+        cache.get_or_insert(&1, "0", Entry::from_parts(1, Some(3), 0));
+        cache.get_or_insert(&2, "0", Entry::from_parts(1, Some(3), 1));
+
+        assert_eq!(cache.get_with_insertion_time(&2, "0"), Some((1, &mut 0)));
+        assert_eq!(cache.get_with_insertion_time(&4, "0"), None);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut cache = SizedTTLCache::<String, usize, usize>::with_capacity(2);
+        cache.get_or_insert(&0, "0", Entry::from_parts(1, None, 0));
+        cache.get_or_insert(&0, "1", Entry::from_parts(1, None, 1));
+
+        cache.retain(|_, value| *value != 0);
+        assert_eq!(cache.values().collect::<Vec<_>>(), vec![&1]);
+
+        // The removed size no longer counts towards the capacity.
+        cache.get_or_insert(&0, "2", Entry::from_parts(1, None, 2));
+        assert_eq!(cache.get(&0, "1"), Some(&mut 1));
+    }
 }
@@ -1,3 +1,4 @@
+use core::iter;
 use core::ops::Range;
 
 use super::range;
@@ -133,6 +134,137 @@ where
         out.into()
     }
 
+    /// Returns an iterator over the mapped blocks, in increasing order of their ranges.
+    pub fn iter(&self) -> impl Iterator<Item = (Range<usize>, &T)> {
+        self.blocks.iter().map(|node| (node.range(), &node.block))
+    }
+
+    /// Returns an iterator over the slices of mapped blocks which intersect `range`,
+    /// in increasing order.
+    ///
+    /// Each slice is adjusted so that it is contained within `range`. Holes within
+    /// `range` are skipped; see [`SparseMap::holes`] to enumerate them.
+    pub fn get_range(
+        &self,
+        range: Range<usize>,
+    ) -> impl Iterator<Item = (Range<usize>, T::Slice)> + '_ {
+        let mut it = self.blocks.upper_bound(Bound::Included(&range.start));
+        if it.is_null() {
+            // Nothing starts at or below the range, so begin at the first block.
+            it.move_next();
+        }
+
+        iter::from_fn(move || loop {
+            let node = it.get().filter(|node| node.start < range.end)?;
+            let node_range = node.range();
+            it.move_next();
+
+            let start = node_range.start.max(range.start);
+            let end = node_range.end.min(range.end);
+
+            if start < end {
+                let slice = node.block.slice((start - node.start)..(end - node.start));
+                return Some((start..end, slice));
+            }
+        })
+    }
+
+    /// Returns an iterator over the holes which intersect `range`, in increasing order.
+    ///
+    /// Each hole is adjusted so that it is contained within `range`. If nothing is
+    /// mapped inside of `range`, then the only hole returned is `range` itself.
+    pub fn holes(&self, range: Range<usize>) -> impl Iterator<Item = Range<usize>> + '_ {
+        let mut it = self.blocks.upper_bound(Bound::Included(&range.start));
+        if it.is_null() {
+            // Nothing starts at or below the range, so begin at the first block.
+            it.move_next();
+        }
+
+        let mut offset = range.start;
+
+        iter::from_fn(move || {
+            while offset < range.end {
+                let node_range = match it.get() {
+                    Some(node) if node.start < range.end => node.range(),
+                    _ => {
+                        // No more blocks intersect, so the remainder is a hole.
+                        let hole = offset..range.end;
+                        offset = range.end;
+
+                        return Some(hole);
+                    }
+                };
+
+                it.move_next();
+
+                if node_range.start > offset {
+                    // There is a hole before this block.
+                    let hole = offset..node_range.start;
+                    offset = node_range.end;
+
+                    return Some(hole);
+                }
+
+                offset = offset.max(node_range.end);
+            }
+
+            None
+        })
+    }
+
+    /// Unmaps all indices within `range`, returning the number of indices which
+    /// were unmapped.
+    ///
+    /// Blocks which only partially intersect `range` are split, and the sections
+    /// outside of `range` are retained as owned copies.
+    pub fn remove(&mut self, range: Range<usize>) -> usize
+    where
+        T: From<T::Slice>,
+    {
+        let mut removed = 0;
+        let mut it = self.blocks.upper_bound_mut(Bound::Included(&range.start));
+
+        match it.get() {
+            Some(node) if node.range().end > range.start => {
+                // The block before or at the start intersects, so start here.
+            }
+            _ => {
+                // Either no block starts at or below the range, or it ends before
+                // the range. Either way, the next block is the first candidate.
+                it.move_next();
+            }
+        }
+
+        while let Some(node) = it.get() {
+            if node.start >= range.end {
+                break;
+            }
+
+            let node = it.remove().unwrap();
+            let node_range = node.range();
+
+            let start = node_range.start.max(range.start);
+            let end = node_range.end.min(range.end);
+            removed += end - start;
+
+            if node_range.start < start {
+                // Retain the section before the range.
+                let block = node.block.slice_unshare(0..(start - node.start));
+                it.insert_before(Node::new(node_range.start, block.into()));
+            }
+
+            if end < node_range.end {
+                // Retain the section after the range.
+                let block = node
+                    .block
+                    .slice_unshare((end - node.start)..node.block.len());
+                it.insert_before(Node::new(end, block.into()));
+            }
+        }
+
+        removed
+    }
+
     /// Returns the number of indices which are covered by any mapped block.
     pub fn mapped_len(&self) -> usize {
         self.blocks.iter().map(|n| n.block.len()).sum()
@@ -415,4 +547,116 @@ mod tests {
         }
         assert_eq!(blocks_found, vec![100..200, 200..300,]);
     }
+
+    #[test]
+    fn test_iter() {
+        let mut map = SparseMap::<usize>::default();
+        assert_eq!(map.iter().count(), 0);
+
+        map.put_new(100, 100);
+        map.put_new(300, 100);
+        map.put_new(0, 50);
+
+        let blocks_found: Vec<_> = map.iter().map(|(range, block)| (range, *block)).collect();
+        assert_eq!(
+            blocks_found,
+            vec![(0..50, 50), (100..200, 100), (300..400, 100)]
+        );
+    }
+
+    #[test]
+    fn test_get_range() {
+        let mut map = SparseMap::<Bytes>::default();
+        map.put_new(100, Bytes::from_static(&[1; 100]));
+        map.put_new(200, Bytes::from_static(&[2; 50]));
+        map.put_new(300, Bytes::from_static(&[3; 100]));
+
+        let slices: Vec<_> = map.get_range(150..350).collect();
+        assert_eq!(
+            slices,
+            vec![
+                (150..200, Bytes::from_static(&[1; 50])),
+                (200..250, Bytes::from_static(&[2; 50])),
+                (300..350, Bytes::from_static(&[3; 50])),
+            ]
+        );
+
+        assert_eq!(map.get_range(0..100).count(), 0);
+        assert_eq!(map.get_range(250..300).count(), 0);
+        assert_eq!(map.get_range(400..500).count(), 0);
+        assert_eq!(
+            map.get_range(0..1000)
+                .map(|(range, _)| range)
+                .collect::<Vec<_>>(),
+            vec![100..200, 200..250, 300..400]
+        );
+    }
+
+    #[test]
+    fn test_holes() {
+        let mut map = SparseMap::<usize>::default();
+        assert_eq!(map.holes(0..100).collect::<Vec<_>>(), vec![0..100]);
+
+        map.put_new(100, 100);
+        map.put_new(300, 100);
+
+        assert_eq!(
+            map.holes(0..500).collect::<Vec<_>>(),
+            vec![0..100, 200..300, 400..500]
+        );
+        assert_eq!(map.holes(150..350).collect::<Vec<_>>(), vec![200..300]);
+        assert_eq!(map.holes(100..200).count(), 0);
+        assert_eq!(map.holes(100..100).count(), 0);
+        assert_eq!(map.holes(450..500).collect::<Vec<_>>(), vec![450..500]);
+
+        map.put_new(200, 100);
+        assert_eq!(map.holes(100..400).count(), 0);
+        assert_eq!(
+            map.holes(50..450).collect::<Vec<_>>(),
+            vec![50..100, 400..450]
+        );
+    }
+
+    #[test]
+    fn test_remove_whole_blocks() {
+        let mut map = SparseMap::<usize>::default();
+        map.put_new(0, 100);
+        map.put_new(100, 100);
+        map.put_new(300, 100);
+
+        assert_eq!(map.remove(100..400), 200);
+        assert_eq!(
+            map.iter().map(|(range, _)| range).collect::<Vec<_>>(),
+            vec![0..100]
+        );
+        assert_eq!(map.mapped_len(), 100);
+
+        assert_eq!(map.remove(500..600), 0);
+        assert_eq!(map.remove(0..100), 100);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_remove_splits_blocks() {
+        let mut map = SparseMap::<Bytes>::default();
+        map.put_new(0, Bytes::from_static(&[1; 100]));
+        map.put_new(100, Bytes::from_static(&[2; 100]));
+
+        assert_eq!(map.remove(50..150), 100);
+        assert_eq!(
+            map.iter().map(|(range, _)| range).collect::<Vec<_>>(),
+            vec![0..50, 150..200]
+        );
+        assert_eq!(map.get(0, 100), Some(Bytes::from_static(&[1; 50])));
+        assert_eq!(map.get(50, 100), None);
+        assert_eq!(map.get(150, 100), Some(Bytes::from_static(&[2; 50])));
+
+        // Removing from the interior of a single block leaves both ends.
+        assert_eq!(map.remove(10..20), 10);
+        assert_eq!(
+            map.iter().map(|(range, _)| range).collect::<Vec<_>>(),
+            vec![0..10, 20..50, 150..200]
+        );
+        assert_eq!(map.holes(0..200).collect::<Vec<_>>(), vec![10..20, 50..150]);
+    }
 }
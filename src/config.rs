@@ -1,22 +1,332 @@
-use clap::Parser;
+use axum::http::{HeaderName, HeaderValue};
+use cache_streamer_http::{Selection, Url};
+use clap::{Parser, ValueEnum};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Config {
-    #[arg(short, long, required = true)]
-    /// Base URL to fetch against, such as "http://example.com"
-    pub url: String,
+    #[arg(short, long, required_unless_present_any = ["routes", "config"])]
+    /// Base URL to fetch against, such as "http://example.com", for requests which
+    /// match no --route. May be given multiple times for mirror origins which serve
+    /// identical content.
+    pub url: Vec<Url>,
+
+    /// Fetch requests for a hostname and path prefix from other origins, as
+    /// "[HOST]/PREFIX=URL[,URL...]", such as "example.com/videos=http://videos.internal".
+    /// Without a hostname, the route matches any hostname. May be given multiple times;
+    /// the most specific route is used. Identical paths are cached separately for each
+    /// route.
+    #[arg(long = "route", value_name = "ROUTE", value_parser = parse_route)]
+    pub routes: Vec<RouteConfig>,
+
+    /// TOML file with further routes, as `[[route]]` tables with `host`, `prefix` and
+    /// `origins` keys. Each route may override the origin policies given on the command
+    /// line, such as `limit` or `max-ttl`. It is reloaded on SIGHUP without clearing the
+    /// cache.
+    #[arg(long, env = "CACHE_STREAMER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Check that the configuration is valid, then exit.
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// How to pick between multiple origins for each request. Requests which fail to
+    /// connect are retried on the other origins.
+    #[arg(long, env = "CACHE_STREAMER_BALANCE", value_enum, default_value_t = Balance::RoundRobin)]
+    pub balance: Balance,
 
     /// Address to bind to for serving HTTP.
     #[arg(short, long, default_value = "127.0.0.1:3000")]
     pub bind_address: String,
 
+    /// PEM file with a certificate chain to serve HTTPS with, rather than HTTP. May be
+    /// given multiple times for several hostnames; the first certificate valid for the
+    /// hostname the client asks for is used, or else the first certificate. Certificates
+    /// are reloaded on SIGHUP.
+    #[arg(long = "tls-cert", value_name = "PATH", requires = "tls_keys")]
+    pub tls_certs: Vec<PathBuf>,
+
+    /// PEM file with the private key of each --tls-cert, given in the same order.
+    #[arg(long = "tls-key", value_name = "PATH", requires = "tls_certs")]
+    pub tls_keys: Vec<PathBuf>,
+
+    /// Path which answers 200 while the server is ready for requests, and 503 once it is
+    /// shutting down, for load balancer health checks, such as "/_ready". Requests for the
+    /// path are not proxied. By default, no such path is served.
+    #[arg(long, value_name = "PATH", value_parser = parse_ready_path)]
+    pub ready_path: Option<String>,
+
+    /// After SIGTERM or SIGINT, keep accepting connections for this many seconds while
+    /// reporting not ready, so that load balancers stop sending requests first.
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    pub shutdown_delay: u64,
+
+    /// Then wait at most this many seconds for requests in flight, such as long streams,
+    /// to finish before exiting.
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub shutdown_timeout: u64,
+
     /// Total capacity of the cache, in MiB.
     #[arg(short, long, default_value_t = 2048)]
     pub capacity: usize,
 
     /// Largest size for which an object can be cached, in MiB.
     /// Larger objects will be passed through instead.
-    #[arg(short, long, default_value_t = 100)]
+    #[arg(short, long, env = "CACHE_STREAMER_LIMIT", default_value_t = 100)]
     pub limit: usize,
+
+    /// Where to store the bodies of cached responses.
+    #[arg(short, long, value_enum, default_value_t = StorageKind::Memory)]
+    pub storage: StorageKind,
+
+    /// Directory for response body files when using file storage.
+    /// Defaults to the system temporary directory.
+    #[arg(long)]
+    pub storage_path: Option<PathBuf>,
+
+    /// Proxy requests with methods other than GET and HEAD to the origin,
+    /// without caching them. Otherwise, they are rejected with 405.
+    #[arg(long, env = "CACHE_STREAMER_PASSTHROUGH_METHODS")]
+    pub passthrough_methods: bool,
+
+    /// Client request header to forward to the origin, such as "x-forwarded-for".
+    /// May be given multiple times, or as a comma-separated list. Responses which do not
+    /// vary on every forwarded header, or any response if "authorization" or "cookie" are
+    /// forwarded, are only cached if marked public, s-maxage or must-revalidate.
+    #[arg(
+        long = "forward-header",
+        env = "CACHE_STREAMER_FORWARD_HEADERS",
+        value_name = "NAME",
+        value_delimiter = ','
+    )]
+    pub forward_headers: Vec<HeaderName>,
+
+    /// Header to send with every request to the origin, as "name: value".
+    /// May be given multiple times.
+    #[arg(
+        long = "inject-header",
+        env = "CACHE_STREAMER_INJECT_HEADER",
+        value_name = "HEADER",
+        value_parser = parse_header
+    )]
+    pub inject_headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Cache 404 and 410 responses from the origin for at most this many seconds.
+    /// By default, they are not cached.
+    #[arg(long, env = "CACHE_STREAMER_NEGATIVE_TTL", value_name = "SECONDS")]
+    pub negative_ttl: Option<u32>,
+
+    /// Largest size for which a 404 or 410 response can be cached, in KiB.
+    /// Larger responses will be passed through instead.
+    #[arg(
+        long,
+        env = "CACHE_STREAMER_NEGATIVE_LIMIT",
+        value_name = "KIB",
+        default_value_t = 64
+    )]
+    pub negative_limit: usize,
+
+    /// Cache responses which the origin does not give a freshness lifetime, such as with
+    /// max-age, for at most this many seconds. By default, they never expire.
+    #[arg(long, env = "CACHE_STREAMER_DEFAULT_TTL", value_name = "SECONDS")]
+    pub default_ttl: Option<u32>,
+
+    /// Cache responses for at most this many seconds, regardless of their freshness
+    /// lifetime.
+    #[arg(long, env = "CACHE_STREAMER_MAX_TTL", value_name = "SECONDS")]
+    pub max_ttl: Option<u32>,
+
+    /// Retry requests on the other origins if the origin does not respond within this many
+    /// seconds. By default, requests only time out while connecting.
+    #[arg(
+        long,
+        env = "CACHE_STREAMER_FIRST_BYTE_TIMEOUT",
+        value_name = "SECONDS"
+    )]
+    pub first_byte_timeout: Option<u64>,
+
+    /// Fetch the rest of a body again from the offset it reached if the origin sends no
+    /// bytes for this many seconds.
+    #[arg(long, env = "CACHE_STREAMER_IDLE_TIMEOUT", value_name = "SECONDS")]
+    pub idle_timeout: Option<u64>,
+
+    /// Fetch the rest of a body again from the offset it reached if the request to the
+    /// origin takes longer than this many seconds in total.
+    #[arg(long, env = "CACHE_STREAMER_TOTAL_TIMEOUT", value_name = "SECONDS")]
+    pub total_timeout: Option<u64>,
+
+    /// PEM file with CA certificates to trust for origins, in addition to the system root
+    /// certificates. May be given multiple times, or as a comma-separated list.
+    #[arg(
+        long,
+        env = "CACHE_STREAMER_UPSTREAM_CA",
+        value_name = "PATH",
+        value_delimiter = ','
+    )]
+    pub upstream_ca: Vec<PathBuf>,
+
+    /// PEM file with a client certificate chain to present to origins which require one.
+    #[arg(
+        long,
+        env = "CACHE_STREAMER_UPSTREAM_CERT",
+        value_name = "PATH",
+        requires = "upstream_key"
+    )]
+    pub upstream_cert: Option<PathBuf>,
+
+    /// PEM file with the PKCS #8 private key of --upstream-cert.
+    #[arg(
+        long,
+        env = "CACHE_STREAMER_UPSTREAM_KEY",
+        value_name = "PATH",
+        requires = "upstream_cert"
+    )]
+    pub upstream_key: Option<PathBuf>,
+
+    /// Hostname to connect to origins given by IP address as, which is sent for SNI,
+    /// checked against their certificates and sent in the host header.
+    #[arg(long, env = "CACHE_STREAMER_UPSTREAM_SERVER_NAME", value_name = "NAME")]
+    pub upstream_server_name: Option<String>,
+
+    /// Connect to an address for origins with a hostname, rather than resolving it, as
+    /// "HOST=IP[:PORT]". May be given multiple times, or as a comma-separated list.
+    #[arg(
+        long,
+        env = "CACHE_STREAMER_RESOLVE",
+        value_name = "HOST=ADDR",
+        value_parser = parse_resolve,
+        value_delimiter = ','
+    )]
+    pub resolve: Vec<(String, SocketAddr)>,
+
+    /// Largest number of variants stored for each path, for responses which vary on
+    /// request headers. The headers must be forwarded with --forward-header for the
+    /// origin to see them.
+    #[arg(long, default_value_t = 16)]
+    pub max_variants: usize,
+
+    /// Re-verify cached bodies against the digests sent by the origin every this many
+    /// seconds, evicting any which no longer match. By default, bodies are only
+    /// verified once, when they are complete.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub scrub_interval: Option<u64>,
+}
+
+pub fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("missing ':' in header \"{s}\""))?;
+    let name = name
+        .trim()
+        .parse::<HeaderName>()
+        .map_err(|e| e.to_string())?;
+    let value = value
+        .trim()
+        .parse::<HeaderValue>()
+        .map_err(|e| e.to_string())?;
+
+    Ok((name, value))
+}
+
+/// A route from requests for a hostname and path prefix to its origins.
+#[derive(Clone, Debug)]
+pub struct RouteConfig {
+    pub host: Option<String>,
+    pub prefix: String,
+    pub urls: Vec<Url>,
+    pub policy: RoutePolicy,
+}
+
+/// Origin policies of a route which override those given on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct RoutePolicy {
+    pub balance: Option<Balance>,
+    pub limit: Option<usize>,
+    pub passthrough_methods: Option<bool>,
+    pub forward_headers: Option<Vec<HeaderName>>,
+    pub inject_headers: Option<Vec<(HeaderName, HeaderValue)>>,
+    pub negative_ttl: Option<u32>,
+    pub negative_limit: Option<usize>,
+    pub default_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+    pub first_byte_timeout: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub total_timeout: Option<u64>,
+    pub upstream_ca: Option<Vec<PathBuf>>,
+    pub upstream_identity: Option<(PathBuf, PathBuf)>,
+    pub upstream_server_name: Option<String>,
+    pub resolve: Option<Vec<(String, SocketAddr)>>,
+}
+
+fn parse_ready_path(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') || s == "/" || s.contains(['*', ':', '{', '}']) {
+        return Err(format!("\"{s}\" must be a plain path under \"/\""));
+    }
+
+    Ok(s.to_owned())
+}
+
+pub fn parse_resolve(s: &str) -> Result<(String, SocketAddr), String> {
+    let (host, addr) = s
+        .split_once('=')
+        .ok_or_else(|| format!("missing '=' in \"{s}\""))?;
+    let addr = match addr.trim().parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(addr.trim().parse::<IpAddr>().map_err(|e| e.to_string())?, 0),
+    };
+
+    Ok((host.trim().to_owned(), addr))
+}
+
+fn parse_route(s: &str) -> Result<RouteConfig, String> {
+    let (pattern, urls) = s
+        .split_once('=')
+        .ok_or_else(|| format!("missing '=' in route \"{s}\""))?;
+    let (host, prefix) = pattern.split_at(pattern.find('/').unwrap_or(pattern.len()));
+    let urls = urls
+        .split(',')
+        .map(|url| url.trim().parse::<Url>().map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(RouteConfig {
+        host: Some(host.trim())
+            .filter(|host| !host.is_empty())
+            .map(str::to_owned),
+        prefix: prefix.trim().to_owned(),
+        urls,
+        policy: RoutePolicy::default(),
+    })
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum StorageKind {
+    /// Heap memory.
+    Memory,
+
+    /// One sparse file per response. Only supported on unix.
+    File,
+
+    /// A single anonymous memory map, sized to the cache capacity plus the
+    /// largest cacheable response.
+    Mmap,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Balance {
+    /// Take turns between origins.
+    RoundRobin,
+
+    /// Pick the origin with the fewest requests in flight.
+    LeastConnections,
+}
+
+impl From<Balance> for Selection {
+    fn from(balance: Balance) -> Self {
+        match balance {
+            Balance::RoundRobin => Selection::RoundRobin,
+            Balance::LeastConnections => Selection::LeastConnections,
+        }
+    }
 }
@@ -0,0 +1,313 @@
+//! Routes loaded from a TOML configuration file, such as:
+//!
+//! ```toml
+//! [[route]]
+//! host = "images.example.com"
+//! origins = ["https://images-a.internal", "https://images-b.internal"]
+//! balance = "least-connections"
+//!
+//! [[route]]
+//! prefix = "/videos"
+//! origins = ["https://videos.internal"]
+//! limit = 4096
+//! max-ttl = 86400
+//! inject-headers = ["authorization: Bearer ${VIDEOS_TOKEN}"]
+//! ```
+//!
+//! Each route takes the same origin policies as the command line, which apply to the
+//! route when it does not set them: `balance`, `limit`, `passthrough-methods`,
+//! `forward-headers`, `inject-headers`, `negative-ttl`, `negative-limit`, `default-ttl`,
+//! `max-ttl`, `first-byte-timeout`, `idle-timeout`, `total-timeout`, `upstream-ca`,
+//! `upstream-cert` with `upstream-key`, `upstream-server-name` and `resolve`. On the
+//! command line, these may also be set with environment variables, such as
+//! `CACHE_STREAMER_MAX_TTL`.
+//!
+//! Origins and injected headers may refer to environment variables as `${NAME}`, so that
+//! secrets can be kept out of the file.
+
+use axum::http::HeaderName;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{parse_header, parse_resolve, Balance, RouteConfig, RoutePolicy};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    route: Vec<RouteEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RouteEntry {
+    host: Option<String>,
+    #[serde(default = "root_prefix")]
+    prefix: String,
+    origins: Vec<String>,
+    balance: Option<String>,
+    limit: Option<usize>,
+    passthrough_methods: Option<bool>,
+    forward_headers: Option<Vec<String>>,
+    inject_headers: Option<Vec<String>>,
+    negative_ttl: Option<u32>,
+    negative_limit: Option<usize>,
+    default_ttl: Option<u32>,
+    max_ttl: Option<u32>,
+    first_byte_timeout: Option<u64>,
+    idle_timeout: Option<u64>,
+    total_timeout: Option<u64>,
+    upstream_ca: Option<Vec<PathBuf>>,
+    upstream_cert: Option<PathBuf>,
+    upstream_key: Option<PathBuf>,
+    upstream_server_name: Option<String>,
+    resolve: Option<Vec<String>>,
+}
+
+fn root_prefix() -> String {
+    "/".into()
+}
+
+/// Load and validate the routes in the configuration file at `path`.
+pub fn load(path: &Path) -> Result<Vec<RouteConfig>, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+
+    parse(&contents).map_err(|e| format!("invalid {}: {e}", path.display()))
+}
+
+/// Parse and validate the routes in the contents of a configuration file.
+fn parse(contents: &str) -> Result<Vec<RouteConfig>, String> {
+    let file: ConfigFile = toml::from_str(contents).map_err(|e| e.to_string())?;
+
+    file.route
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| into_route(entry).map_err(|e| format!("route {}: {e}", i + 1)))
+        .collect()
+}
+
+/// Validate a route from the file.
+fn into_route(entry: RouteEntry) -> Result<RouteConfig, String> {
+    if !entry.prefix.starts_with('/') {
+        return Err(format!("prefix \"{}\" must start with '/'", entry.prefix));
+    }
+
+    if let Some(host) = &entry.host {
+        host.parse::<axum::http::uri::Authority>()
+            .map_err(|e| format!("invalid host \"{host}\": {e}"))?;
+    }
+
+    if entry.origins.is_empty() {
+        return Err("no origins".into());
+    }
+
+    let urls = entry
+        .origins
+        .iter()
+        .map(|origin| {
+            let origin = expand_env(origin)?;
+            origin
+                .parse()
+                .map_err(|e| format!("invalid origin \"{origin}\": {e}"))
+        })
+        .collect::<Result<_, String>>()?;
+
+    let balance = entry
+        .balance
+        .map(|balance| Balance::from_str(&balance, true))
+        .transpose()?;
+
+    let forward_headers = entry
+        .forward_headers
+        .map(|names| {
+            names
+                .iter()
+                .map(|name| {
+                    name.parse::<HeaderName>()
+                        .map_err(|e| format!("invalid header name \"{name}\": {e}"))
+                })
+                .collect::<Result<_, _>>()
+        })
+        .transpose()?;
+
+    let inject_headers = entry
+        .inject_headers
+        .map(|headers| {
+            headers
+                .iter()
+                .map(|header| parse_header(&expand_env(header)?))
+                .collect::<Result<_, _>>()
+        })
+        .transpose()?;
+
+    let upstream_identity = match (entry.upstream_cert, entry.upstream_key) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => return Err("upstream-cert and upstream-key must be given together".into()),
+    };
+
+    let resolve = entry
+        .resolve
+        .map(|resolve| resolve.iter().map(|s| parse_resolve(s)).collect())
+        .transpose()?;
+
+    Ok(RouteConfig {
+        host: entry.host,
+        prefix: entry.prefix,
+        urls,
+        policy: RoutePolicy {
+            balance,
+            limit: entry.limit,
+            passthrough_methods: entry.passthrough_methods,
+            forward_headers,
+            inject_headers,
+            negative_ttl: entry.negative_ttl,
+            negative_limit: entry.negative_limit,
+            default_ttl: entry.default_ttl,
+            max_ttl: entry.max_ttl,
+            first_byte_timeout: entry.first_byte_timeout,
+            idle_timeout: entry.idle_timeout,
+            total_timeout: entry.total_timeout,
+            upstream_ca: entry.upstream_ca,
+            upstream_identity,
+            upstream_server_name: entry.upstream_server_name,
+            resolve,
+        },
+    })
+}
+
+/// Replace references to environment variables, such as `${NAME}`, with their values.
+fn expand_env(s: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated variable in \"{s}\""))?;
+        let name = &rest[start + 2..start + end];
+        let value =
+            env::var(name).map_err(|_| format!("environment variable {name} is not set"))?;
+
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let routes = parse(
+            r#"
+            [[route]]
+            host = "example.com"
+            prefix = "/videos"
+            origins = ["http://a.internal", "http://b.internal"]
+            balance = "least-connections"
+            max-ttl = 60
+            forward-headers = ["x-forwarded-for"]
+            inject-headers = ["x-token: secret"]
+
+            [[route]]
+            origins = ["http://c.internal"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].host.as_deref(), Some("example.com"));
+        assert_eq!(routes[0].prefix, "/videos");
+        assert_eq!(routes[0].urls.len(), 2);
+        assert!(matches!(
+            routes[0].policy.balance,
+            Some(Balance::LeastConnections)
+        ));
+        assert_eq!(routes[0].policy.max_ttl, Some(60));
+        assert_eq!(routes[0].policy.inject_headers.as_ref().unwrap().len(), 1);
+
+        // Unset policies are taken from the command line.
+        assert_eq!(routes[1].host, None);
+        assert_eq!(routes[1].prefix, "/");
+        assert_eq!(routes[1].policy.max_ttl, None);
+        assert!(routes[1].policy.forward_headers.is_none());
+
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let e = parse("[[route]]\norigins = [\"http://a\"]\nmax_ttl = 60").unwrap_err();
+        assert!(e.contains("unknown field `max_ttl`"), "{e}");
+
+        let e = parse("[[routes]]\norigins = [\"http://a\"]").unwrap_err();
+        assert!(e.contains("unknown field `routes`"), "{e}");
+    }
+
+    #[test]
+    fn test_invalid_routes() {
+        let e = parse("[[route]]\nprefix = \"videos\"\norigins = [\"http://a\"]").unwrap_err();
+        assert_eq!(e, "route 1: prefix \"videos\" must start with '/'");
+
+        let e = parse("[[route]]\nhost = \"a b\"\norigins = [\"http://a\"]").unwrap_err();
+        assert!(e.starts_with("route 1: invalid host \"a b\""), "{e}");
+
+        let e = parse("[[route]]\norigins = [\"http://a\"]\n[[route]]\norigins = []").unwrap_err();
+        assert_eq!(e, "route 2: no origins");
+
+        let e = parse("[[route]]\norigins = [\"a\"]").unwrap_err();
+        assert!(e.starts_with("route 1: invalid origin \"a\""), "{e}");
+
+        let e = parse("[[route]]\norigins = [\"http://a\"]\nbalance = \"random\"").unwrap_err();
+        assert!(e.starts_with("route 1: "), "{e}");
+
+        let e =
+            parse("[[route]]\norigins = [\"http://a\"]\nupstream-cert = \"a.crt\"").unwrap_err();
+        assert_eq!(
+            e,
+            "route 1: upstream-cert and upstream-key must be given together"
+        );
+    }
+
+    #[test]
+    fn test_expand_env() {
+        env::set_var("CACHE_STREAMER_TEST_TOKEN", "secret");
+
+        assert_eq!(expand_env("plain").unwrap(), "plain");
+        assert_eq!(
+            expand_env("Bearer ${CACHE_STREAMER_TEST_TOKEN}!").unwrap(),
+            "Bearer secret!"
+        );
+        assert_eq!(
+            expand_env("${CACHE_STREAMER_TEST_TOKEN}${CACHE_STREAMER_TEST_TOKEN}").unwrap(),
+            "secretsecret"
+        );
+
+        assert_eq!(
+            expand_env("Bearer ${CACHE_STREAMER_TEST_TOKEN").unwrap_err(),
+            "unterminated variable in \"Bearer ${CACHE_STREAMER_TEST_TOKEN\""
+        );
+        assert_eq!(
+            expand_env("${CACHE_STREAMER_TEST_UNSET}").unwrap_err(),
+            "environment variable CACHE_STREAMER_TEST_UNSET is not set"
+        );
+
+        let routes = parse(
+            "[[route]]\norigins = [\"http://a\"]\ninject-headers = [\"x-token: ${CACHE_STREAMER_TEST_TOKEN}\"]",
+        )
+        .unwrap();
+        let headers = routes[0].policy.inject_headers.as_ref().unwrap();
+        assert_eq!(headers[0].1, "secret");
+    }
+}
@@ -1,9 +1,21 @@
+use cache_streamer_http::{RouteTable, StorageBackend};
 use clap::Parser;
 use config::Config;
-use std::env;
+use std::sync::Arc;
+use std::{env, process};
+use tls::CertificateResolver;
 
 mod config;
+mod config_file;
 mod server;
+mod shutdown;
+mod tls;
+
+type Loaded = (
+    RouteTable,
+    Arc<dyn StorageBackend>,
+    Option<Arc<CertificateResolver>>,
+);
 
 fn main() {
     if env::var("RUST_LOG").is_err() {
@@ -12,5 +24,34 @@ fn main() {
 
     tracing_subscriber::fmt::init();
 
-    server::run(&Config::parse());
+    let config = Config::parse();
+    let (routes, storage, tls) = match load(&config) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("invalid configuration: {e}");
+            process::exit(1);
+        }
+    };
+
+    if config.check_config {
+        println!("configuration is valid");
+        return;
+    }
+
+    server::run(config, routes, storage, tls);
+}
+
+/// Load the routes, storage and certificates, checking that the configuration is valid.
+fn load(config: &Config) -> Result<Loaded, String> {
+    let routes = server::build_routes(config)?;
+    let storage = server::storage_backend(config)?;
+    let tls = match config.tls_certs.is_empty() {
+        true => None,
+        false => Some(Arc::new(CertificateResolver::load(
+            &config.tls_certs,
+            &config.tls_keys,
+        )?)),
+    };
+
+    Ok((routes, storage, tls))
 }
@@ -1,42 +1,342 @@
 use axum::{
     body::Body,
-    extract::{Path, Request, State},
-    http::{Method, StatusCode},
+    extract::{ConnectInfo, Path, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
     response::IntoResponse,
-    routing::get,
+    routing::{any, get},
     Router,
 };
-use cache_streamer_http::{HTTPRequestBackend, HTTPService, Url};
+#[cfg(unix)]
+use cache_streamer_http::storage::FileStorageBackend;
+use cache_streamer_http::storage::{MemoryStorageBackend, MmapArena};
+use cache_streamer_http::{
+    ClientOptions, HTTPRequestBackend, HTTPService, OriginPool, Route, RouteTable, StorageBackend,
+    TimeDelta,
+};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path as FilePath;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
 use tower_http::trace::TraceLayer;
 
-use crate::Config;
+use crate::config::{Config, RouteConfig, RoutePolicy, StorageKind};
+use crate::config_file;
+use crate::shutdown::Shutdown;
+use crate::tls::{self, CertificateResolver};
 
+const UNIT_KIB: usize = 1 << 10;
 const UNIT_MIB: usize = 1 << 20;
 
 #[tokio::main]
-pub async fn run(config: &Config) {
-    let base_url = config.url.parse::<Url>().unwrap();
-    let backend = HTTPRequestBackend::new(base_url, config.limit * UNIT_MIB);
-    let service = HTTPService::new(Arc::new(backend), config.capacity * UNIT_MIB);
+pub async fn run(
+    config: Config,
+    routes: RouteTable,
+    storage: Arc<dyn StorageBackend>,
+    tls: Option<Arc<CertificateResolver>>,
+) {
+    let service = HTTPService::with_storage(routes, storage, config.capacity * UNIT_MIB)
+        .with_max_variants(config.max_variants);
+
+    let service = Arc::new(service);
+
+    if config.config.is_some() || tls.is_some() {
+        tokio::spawn(reload_on_hangup(
+            service.clone(),
+            config.clone(),
+            tls.clone(),
+        ));
+    }
+
+    if let Some(interval) = config.scrub_interval {
+        tokio::spawn(scrub(service.clone(), Duration::from_secs(interval)));
+    }
 
-    let app = Router::new()
+    let shutdown = Arc::new(Shutdown::new());
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        let delay = Duration::from_secs(config.shutdown_delay);
+        async move { shutdown.on_signal(delay).await }
+    });
+
+    let mut app = Router::new()
         .route("/", get(root).head(root))
-        .route("/*path", get(call).head(call))
-        .fallback(client_error)
-        .layer(TraceLayer::new_for_http());
+        .route("/*path", any(call));
 
-    let app = app.with_state(Arc::new(service));
+    if let Some(path) = &config.ready_path {
+        let handler = {
+            let shutdown = shutdown.clone();
+            move |req: Request| ready(shutdown.clone(), req)
+        };
+        app = app.route(path, get(handler.clone()).head(handler));
+    }
+
+    let app = app
+        .fallback(client_error)
+        .layer(TraceLayer::new_for_http())
+        .with_state(service);
     let listener = TcpListener::bind(&config.bind_address).await.unwrap();
+    let stopping = {
+        let shutdown = shutdown.clone();
+        async move { shutdown.stopping().await }
+    };
+
+    let serve = async move {
+        match tls {
+            Some(resolver) => tls::serve(listener, resolver, app, stopping).await,
+            None => axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(stopping)
+            .await
+            .unwrap(),
+        }
+    };
+
+    // Once draining, give up on the remaining requests after the timeout, or on another
+    // signal.
+    let deadline = async {
+        shutdown.stopping().await;
+
+        tokio::select! {
+            () = tokio::time::sleep(Duration::from_secs(config.shutdown_timeout)) => {
+                tracing::warn!("timed out waiting for requests in flight, exiting");
+            }
+            () = shutdown.exiting() => {}
+        }
+    };
+
+    tokio::select! {
+        () = serve => tracing::info!("all requests finished, exiting"),
+        () = deadline => {}
+    }
+}
+
+/// Periodically verify the bodies stored by the cache, evicting any which no longer match.
+async fn scrub(service: Arc<HTTPService>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let service = service.clone();
+        match tokio::task::spawn_blocking(move || service.scrub()).await {
+            Ok(0) => {}
+            Ok(evicted) => tracing::warn!("scrub evicted {evicted} corrupted responses"),
+            Err(e) => tracing::error!("scrub failed: {e}"),
+        }
+    }
+}
+
+/// Build the routes from the configuration file, then the `--route` arguments, then the
+/// `--url` arguments, which match any request.
+pub fn build_routes(config: &Config) -> Result<RouteTable, String> {
+    let mut routes = match &config.config {
+        Some(path) => config_file::load(path)?,
+        None => Vec::new(),
+    };
+
+    routes.extend(config.routes.iter().cloned());
+
+    if !config.url.is_empty() {
+        routes.push(RouteConfig {
+            host: None,
+            prefix: "/".into(),
+            urls: config.url.clone(),
+            policy: RoutePolicy::default(),
+        });
+    }
+
+    let mut names = HashSet::new();
+    let mut table = RouteTable::new();
+
+    for route in &routes {
+        let route = route_for(config, route)?;
+
+        if !names.insert(route.name().to_owned()) {
+            return Err(format!("duplicate route {}", route.name()));
+        }
+
+        table = table.with_route(route);
+    }
+
+    Ok(table)
+}
+
+/// Build a route, taking any policies which it does not set from the command line.
+fn route_for(config: &Config, route: &RouteConfig) -> Result<Route, String> {
+    let policy = &route.policy;
+    let balance = policy.balance.unwrap_or(config.balance);
+    let limit = policy.limit.unwrap_or(config.limit);
+    let forward_headers = policy
+        .forward_headers
+        .as_ref()
+        .unwrap_or(&config.forward_headers);
+    let inject_headers = policy
+        .inject_headers
+        .as_ref()
+        .unwrap_or(&config.inject_headers);
+    let ttl = |ttl: Option<u32>, default: Option<u32>| {
+        ttl.or(default).map(|ttl| TimeDelta::seconds(ttl.into()))
+    };
+
+    let origins = OriginPool::new(route.urls.clone())
+        .with_selection(balance.into())
+        .with_client_options(&client_options(config, policy)?);
+    let mut backend = HTTPRequestBackend::new(origins, limit * UNIT_MIB)
+        .with_forwarded_headers(forward_headers.clone())
+        .with_injected_headers(inject_headers.iter().cloned().collect());
+
+    if let Some(ttl) = ttl(policy.negative_ttl, config.negative_ttl) {
+        let negative_limit = policy.negative_limit.unwrap_or(config.negative_limit);
+        backend = backend.with_negative_caching(ttl, negative_limit * UNIT_KIB);
+    }
+
+    if let Some(ttl) = ttl(policy.default_ttl, config.default_ttl) {
+        backend = backend.with_default_ttl(ttl);
+    }
+
+    if let Some(ttl) = ttl(policy.max_ttl, config.max_ttl) {
+        backend = backend.with_max_ttl(ttl);
+    }
+
+    let timeout =
+        |timeout: Option<u64>, default: Option<u64>| timeout.or(default).map(Duration::from_secs);
+    backend = backend.with_timeouts(
+        timeout(policy.first_byte_timeout, config.first_byte_timeout),
+        timeout(policy.idle_timeout, config.idle_timeout),
+        timeout(policy.total_timeout, config.total_timeout),
+    );
 
-    axum::serve(listener, app).await.unwrap();
+    let backend = Arc::new(backend);
+    let passthrough = policy
+        .passthrough_methods
+        .unwrap_or(config.passthrough_methods);
+    let result = Route::new(route.host.as_deref(), &route.prefix, backend.clone());
+
+    Ok(match passthrough {
+        true => result.with_passthrough(backend),
+        false => result,
+    })
+}
+
+/// Build the options for connecting to the origins of a route, reading any certificates.
+fn client_options(config: &Config, policy: &RoutePolicy) -> Result<ClientOptions, String> {
+    let ca = policy.upstream_ca.as_ref().unwrap_or(&config.upstream_ca);
+    let identity = match &policy.upstream_identity {
+        Some((cert, key)) => Some((cert, key)),
+        None => config
+            .upstream_cert
+            .as_ref()
+            .zip(config.upstream_key.as_ref()),
+    };
+    let server_name = policy
+        .upstream_server_name
+        .as_ref()
+        .or(config.upstream_server_name.as_ref());
+    let resolve = policy.resolve.as_ref().unwrap_or(&config.resolve);
+
+    let mut options = ClientOptions::new();
+
+    for path in ca {
+        options = options
+            .with_root_certificates(&read(path)?)
+            .map_err(|e| format!("invalid certificate {}: {e}", path.display()))?;
+    }
+
+    if let Some((cert, key)) = identity {
+        options = options
+            .with_identity(&read(cert)?, &read(key)?)
+            .map_err(|e| format!("invalid client certificate {}: {e}", cert.display()))?;
+    }
+
+    if let Some(name) = server_name {
+        options = options.with_server_name(name);
+    }
+
+    for (host, addr) in resolve {
+        options = options.with_resolve(host, *addr);
+    }
+
+    Ok(options)
+}
+
+fn read(path: &FilePath) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("failed to read {}: {e}", path.display()))
+}
+
+/// Rebuild the routes from the configuration file and reload the certificates whenever the
+/// process receives `SIGHUP`, keeping the cache and any responses in flight. If the new
+/// configuration is invalid, the current routes or certificates are kept.
+async fn reload_on_hangup(
+    service: Arc<HTTPService>,
+    config: Config,
+    tls: Option<Arc<CertificateResolver>>,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("failed to listen for SIGHUP: {e}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        if config.config.is_some() {
+            match build_routes(&config) {
+                Ok(routes) => {
+                    service.set_routes(routes);
+                    tracing::info!("reloaded configuration");
+                }
+                Err(e) => tracing::error!("failed to reload configuration: {e}"),
+            }
+        }
+
+        if let Some(resolver) = &tls {
+            match resolver.reload() {
+                Ok(()) => tracing::info!("reloaded certificates"),
+                Err(e) => tracing::error!("failed to reload certificates: {e}"),
+            }
+        }
+    }
+}
+
+/// Create the storage backend for the bodies of cached responses.
+pub fn storage_backend(config: &Config) -> Result<Arc<dyn StorageBackend>, String> {
+    Ok(match config.storage {
+        StorageKind::Memory => Arc::new(MemoryStorageBackend),
+        #[cfg(unix)]
+        StorageKind::File => Arc::new(FileStorageBackend::new(
+            config.storage_path.clone().unwrap_or_else(env::temp_dir),
+        )),
+        #[cfg(not(unix))]
+        StorageKind::File => return Err("file storage is only supported on unix".into()),
+        StorageKind::Mmap => {
+            let capacity = (config.capacity + config.limit) * UNIT_MIB;
+            let arena = MmapArena::with_capacity(capacity)
+                .map_err(|e| format!("failed to create the mmap arena: {e}"))?;
+            Arc::new(arena)
+        }
+    })
 }
 
 async fn root(req: Request) -> impl IntoResponse {
     error(&req, StatusCode::NOT_FOUND)
 }
 
+async fn ready(shutdown: Arc<Shutdown>, req: Request) -> impl IntoResponse {
+    match shutdown.is_ready() {
+        true => error(&req, StatusCode::OK),
+        false => error(&req, StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
 async fn client_error(req: Request) -> impl IntoResponse {
     error(&req, StatusCode::BAD_REQUEST)
 }
@@ -55,12 +355,71 @@ fn error(req: &Request, status: StatusCode) -> impl IntoResponse {
 async fn call(
     service: State<Arc<HTTPService>>,
     Path(path): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     req: Request,
 ) -> impl IntoResponse {
+    let (mut parts, body) = req.into_parts();
+    append_forwarded_for(&mut parts.headers, addr);
+
+    // HTTP/2 requests carry the hostname in the URI rather than the `host` header.
+    if let Some(authority) = parts.uri.authority() {
+        if let Ok(value) = HeaderValue::from_str(authority.as_str()) {
+            parts.headers.entry(header::HOST).or_insert(value);
+        }
+    }
+
     let (status, headers, body) = service
-        .call(req.method(), &path, req.headers())
+        .call(
+            &parts.method,
+            &format!("/{path}"),
+            parts.uri.query(),
+            &parts.headers,
+            body.into_data_stream(),
+        )
         .await
         .into_parts();
 
     (status, headers, Body::from_stream(body))
 }
+
+/// Append the client address to the `x-forwarded-for` header of a request.
+fn append_forwarded_for(headers: &mut HeaderMap, addr: SocketAddr) {
+    const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+    let mut addrs: Vec<String> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .collect();
+    addrs.push(addr.ip().to_string());
+
+    if let Ok(value) = HeaderValue::from_str(&addrs.join(", ")) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    /// Build the routes for the command line `args`.
+    fn build(args: &[&str]) -> Result<(), String> {
+        let config = Config::try_parse_from([&["cache_streamer"], args].concat()).unwrap();
+        build_routes(&config).map(|_| ())
+    }
+
+    #[test]
+    fn test_build_routes() {
+        assert!(build(&["-u", "http://a", "--route", "example.com/a=http://b"]).is_ok());
+
+        let e = build(&["--route", "/a=http://a", "--route", "/a=http://b"]).unwrap_err();
+        assert_eq!(e, "duplicate route */a");
+
+        // The catch-all route of --url is a duplicate of a route for any hostname under "/".
+        let e = build(&["-u", "http://a", "--route", "/=http://b"]).unwrap_err();
+        assert_eq!(e, "duplicate route */");
+    }
+}
@@ -0,0 +1,171 @@
+//! Graceful shutdown once the process receives SIGTERM or SIGINT.
+
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+
+/// How far the server has got in shutting down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum State {
+    /// Ready for requests.
+    Running,
+
+    /// Reporting not ready, but still accepting connections.
+    Delaying,
+
+    /// No longer accepting connections, and draining requests in flight.
+    Stopping,
+
+    /// Giving up on the remaining requests.
+    Exiting,
+}
+
+/// Whether the server is ready for requests, and whether it should stop accepting
+/// connections, shared between the listener and the readiness path.
+pub struct Shutdown {
+    state: watch::Sender<State>,
+}
+
+impl Shutdown {
+    /// Create a new [`Shutdown`] for a server which is ready for requests.
+    pub fn new() -> Self {
+        Self {
+            state: watch::Sender::new(State::Running),
+        }
+    }
+
+    /// Returns whether the server is ready for requests, which is no longer the case once
+    /// it has been asked to shut down.
+    pub fn is_ready(&self) -> bool {
+        *self.state.borrow() == State::Running
+    }
+
+    /// Wait until the server should stop accepting connections.
+    pub async fn stopping(&self) {
+        self.wait_for(State::Stopping).await;
+    }
+
+    /// Wait until the server should exit without waiting for requests in flight.
+    pub async fn exiting(&self) {
+        self.wait_for(State::Exiting).await;
+    }
+
+    /// Wait for SIGTERM or SIGINT, then report that the server is not ready, so that load
+    /// balancers stop sending it requests, and stop accepting connections after `delay`.
+    /// Another signal at any point after the first makes the server exit.
+    pub async fn on_signal(&self, delay: Duration) {
+        match signals() {
+            Ok(signals) => self.on(signals, delay).await,
+            Err(e) => tracing::error!("failed to listen for SIGTERM and SIGINT: {e}"),
+        }
+    }
+
+    async fn on(&self, mut signals: mpsc::UnboundedReceiver<()>, delay: Duration) {
+        if signals.recv().await.is_none() {
+            return;
+        }
+
+        self.state.send_replace(State::Delaying);
+        tracing::info!("shutting down, reporting not ready");
+
+        tokio::select! {
+            () = tokio::time::sleep(delay) => {
+                tracing::info!("no longer accepting connections, draining requests in flight");
+                self.state.send_replace(State::Stopping);
+            }
+            Some(()) = signals.recv() => {
+                tracing::warn!("received another signal, exiting");
+                self.state.send_replace(State::Exiting);
+                return;
+            }
+        }
+
+        if signals.recv().await.is_some() {
+            tracing::warn!("received another signal, exiting");
+            self.state.send_replace(State::Exiting);
+        }
+    }
+
+    async fn wait_for(&self, state: State) {
+        let mut receiver = self.state.subscribe();
+        let _ = receiver.wait_for(|current| *current >= state).await;
+    }
+}
+
+/// Listen for SIGTERM and SIGINT, which are each sent to the returned channel.
+fn signals() -> std::io::Result<mpsc::UnboundedReceiver<()>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = interrupt.recv() => {}
+            }
+
+            if sender.send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Start listening for signals from the returned sender.
+    fn start(delay: Duration) -> (Arc<Shutdown>, mpsc::UnboundedSender<()>) {
+        let shutdown = Arc::new(Shutdown::new());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.on(receiver, delay).await }
+        });
+
+        (shutdown, sender)
+    }
+
+    /// Returns whether `future` completes within a short time.
+    async fn completes(future: impl std::future::Future<Output = ()>) -> bool {
+        tokio::time::timeout(Duration::from_millis(100), future)
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (shutdown, signal) = start(Duration::from_millis(50));
+        assert!(shutdown.is_ready());
+        assert!(!completes(shutdown.stopping()).await);
+
+        signal.send(()).unwrap();
+        assert!(completes(shutdown.stopping()).await);
+        assert!(!shutdown.is_ready());
+        assert!(!completes(shutdown.exiting()).await);
+
+        signal.send(()).unwrap();
+        assert!(completes(shutdown.exiting()).await);
+    }
+
+    #[tokio::test]
+    async fn test_signal_during_delay() {
+        let (shutdown, signal) = start(Duration::from_secs(60));
+
+        signal.send(()).unwrap();
+        assert!(!completes(shutdown.stopping()).await);
+        assert!(!shutdown.is_ready());
+
+        // Another signal exits without waiting for the delay.
+        signal.send(()).unwrap();
+        assert!(completes(shutdown.exiting()).await);
+        assert!(completes(shutdown.stopping()).await);
+    }
+}
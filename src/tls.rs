@@ -0,0 +1,370 @@
+//! TLS termination for the listener, with certificates picked by the hostname the client
+//! asks for (SNI) and HTTP/2 negotiated with ALPN.
+
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+
+/// Longest time for a client to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Picks the certificate for each connection from those loaded from PEM files, by the
+/// hostname the client asks for.
+pub struct CertificateResolver {
+    provider: Arc<CryptoProvider>,
+    paths: Vec<(PathBuf, PathBuf)>,
+    keys: RwLock<Arc<Vec<Arc<CertifiedKey>>>>,
+}
+
+impl CertificateResolver {
+    /// Load the certificate chain in each of `certs` with the private key at the same
+    /// position in `keys`.
+    pub fn load(certs: &[PathBuf], keys: &[PathBuf]) -> Result<Self, String> {
+        if certs.len() != keys.len() {
+            return Err(format!(
+                "{} certificates were given with {} keys",
+                certs.len(),
+                keys.len()
+            ));
+        }
+
+        let resolver = Self {
+            provider: Arc::new(ring::default_provider()),
+            paths: certs.iter().cloned().zip(keys.iter().cloned()).collect(),
+            keys: RwLock::default(),
+        };
+        resolver.reload()?;
+
+        Ok(resolver)
+    }
+
+    /// Load the certificates again from their files, such as after they are renewed.
+    /// If any fail to load, the current certificates are kept.
+    pub fn reload(&self) -> Result<(), String> {
+        let keys = self
+            .paths
+            .iter()
+            .map(|(cert, key)| self.load_key(cert, key).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        *self.keys.write().unwrap() = Arc::new(keys);
+
+        Ok(())
+    }
+
+    fn load_key(&self, cert: &Path, key: &Path) -> Result<CertifiedKey, String> {
+        let chain = rustls_pemfile::certs(&mut open(cert)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid certificate {}: {e}", cert.display()))?;
+
+        if chain.is_empty() {
+            return Err(format!("no certificates in {}", cert.display()));
+        }
+
+        let private_key = rustls_pemfile::private_key(&mut open(key)?)
+            .map_err(|e| format!("invalid private key {}: {e}", key.display()))?
+            .ok_or_else(|| format!("no private key in {}", key.display()))?;
+
+        CertifiedKey::from_der(chain, private_key, &self.provider)
+            .map_err(|e| format!("invalid certificate {}: {e}", cert.display()))
+    }
+
+    fn server_config(self: Arc<Self>) -> ServerConfig {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("default protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(self);
+
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        config
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    /// Pick the first certificate which is valid for the requested hostname, or the first
+    /// certificate if none are.
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let keys = self.keys.read().unwrap().clone();
+        let name = hello
+            .server_name()
+            .and_then(|name| ServerName::try_from(name).ok());
+
+        name.and_then(|name| keys.iter().find(|key| is_valid_for(key, &name)))
+            .or(keys.first())
+            .cloned()
+    }
+}
+
+impl fmt::Debug for CertificateResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertificateResolver")
+            .field("paths", &self.paths)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Serve `app` over TLS on `listener`, with certificates from `resolver`.
+///
+/// Once `stopping` completes, no more connections are accepted, idle connections are
+/// closed, and this returns once every other connection has finished its requests.
+pub async fn serve(
+    listener: TcpListener,
+    resolver: Arc<CertificateResolver>,
+    app: Router,
+    stopping: impl Future<Output = ()>,
+) {
+    let acceptor = TlsAcceptor::from(Arc::new(resolver.server_config()));
+    let mut make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(stopping);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = &mut stopping => break,
+        };
+
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("failed to accept connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let watcher = graceful.watcher();
+        let service = make_service.call(addr).await.unwrap_or_else(|e| match e {});
+
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        tracing::debug!("TLS handshake with {addr} failed: {e}");
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::debug!("TLS handshake with {addr} timed out");
+                        return;
+                    }
+                };
+
+            // Serve HTTP/2 if the client negotiated it, or HTTP/1.1 otherwise.
+            let builder = Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(
+                TokioIo::new(stream),
+                TowerToHyperService::new(service),
+            );
+            let result = watcher.watch(connection).await;
+
+            if let Err(e) = result {
+                tracing::debug!("connection with {addr} failed: {e}");
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))
+}
+
+/// Returns whether the end-entity certificate of `key` is valid for `name`.
+fn is_valid_for(key: &CertifiedKey, name: &ServerName) -> bool {
+    key.end_entity_cert()
+        .ok()
+        .and_then(|cert| webpki::EndEntityCert::try_from(cert).ok())
+        .is_some_and(|cert| cert.verify_is_valid_for_subject_name(name).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DnType, KeyPair};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::client::danger::{
+        HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+    };
+    use tokio_rustls::rustls::pki_types::{CertificateDer, UnixTime};
+    use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    /// A self-signed certificate for `name`, written to PEM files named after `test`.
+    struct TestCertificate {
+        der: CertificateDer<'static>,
+        cert: PathBuf,
+        key: PathBuf,
+    }
+
+    fn test_certificate(test: &str, name: &str) -> TestCertificate {
+        let mut params = CertificateParams::new(vec![name.to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        let key_pair = KeyPair::generate().unwrap();
+        let certificate = params.self_signed(&key_pair).unwrap();
+
+        let prefix = format!("cache_streamer_{}_{test}_{name}", std::process::id());
+        let cert = std::env::temp_dir().join(format!("{prefix}.crt"));
+        let key = std::env::temp_dir().join(format!("{prefix}.key"));
+        std::fs::write(&cert, certificate.pem()).unwrap();
+        std::fs::write(&key, key_pair.serialize_pem()).unwrap();
+
+        TestCertificate {
+            der: certificate.der().clone(),
+            cert,
+            key,
+        }
+    }
+
+    impl Drop for TestCertificate {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.cert);
+            let _ = std::fs::remove_file(&self.key);
+        }
+    }
+
+    fn load(certificates: &[&TestCertificate]) -> Result<CertificateResolver, String> {
+        let certs: Vec<_> = certificates.iter().map(|c| c.cert.clone()).collect();
+        let keys: Vec<_> = certificates.iter().map(|c| c.key.clone()).collect();
+        CertificateResolver::load(&certs, &keys)
+    }
+
+    /// Accepts any server certificate, so that tests can see which one was presented.
+    #[derive(Debug)]
+    struct AcceptAny(Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for AcceptAny {
+        fn verify_server_cert(
+            &self,
+            _: &CertificateDer<'_>,
+            _: &[CertificateDer<'_>],
+            _: &ServerName<'_>,
+            _: &[u8],
+            _: UnixTime,
+        ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    /// Complete a handshake with `resolver` for `name`, and return the certificate which
+    /// the server presented.
+    async fn presented(resolver: Arc<CertificateResolver>, name: &str) -> CertificateDer<'static> {
+        let provider = resolver.provider.clone();
+        let acceptor = TlsAcceptor::from(Arc::new(resolver.server_config()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = acceptor.accept(stream).await;
+        });
+
+        let config = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAny(provider)))
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from(name.to_owned()).unwrap();
+        let stream = connector.connect(name, stream).await.unwrap();
+
+        stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let a = test_certificate("resolve", "a.test");
+        let b = test_certificate("resolve", "b.test");
+        let resolver = Arc::new(load(&[&a, &b]).unwrap());
+
+        assert_eq!(presented(resolver.clone(), "a.test").await, a.der);
+        assert_eq!(presented(resolver.clone(), "b.test").await, b.der);
+
+        // Without a certificate for the name, or without a name, the first is presented.
+        assert_eq!(presented(resolver.clone(), "c.test").await, a.der);
+        assert_eq!(presented(resolver, "127.0.0.1").await, a.der);
+    }
+
+    #[test]
+    fn test_reload() {
+        let a = test_certificate("reload", "a.test");
+        let resolver = load(&[&a]).unwrap();
+        let keys = resolver.keys.read().unwrap().clone();
+
+        // Files which fail to load keep the current certificates.
+        std::fs::write(&a.cert, "invalid").unwrap();
+        assert!(resolver.reload().is_err());
+        assert!(Arc::ptr_eq(&keys, &resolver.keys.read().unwrap()));
+
+        // Renewed certificates replace them.
+        let renewed = test_certificate("reload", "b.test");
+        std::fs::copy(&renewed.cert, &a.cert).unwrap();
+        std::fs::copy(&renewed.key, &a.key).unwrap();
+        resolver.reload().unwrap();
+
+        let keys = resolver.keys.read().unwrap().clone();
+        assert_eq!(keys[0].end_entity_cert().unwrap(), &renewed.der);
+    }
+
+    #[test]
+    fn test_load() {
+        let a = test_certificate("load", "a.test");
+
+        let e = CertificateResolver::load(std::slice::from_ref(&a.cert), &[]).unwrap_err();
+        assert_eq!(e, "1 certificates were given with 0 keys");
+
+        // The private key is not a certificate.
+        let keys = std::slice::from_ref(&a.key);
+        let e = CertificateResolver::load(keys, keys).unwrap_err();
+        assert!(e.starts_with("no certificates in"));
+    }
+}